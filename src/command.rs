@@ -1,12 +1,20 @@
-use std::fs;
-use std::io::Read;
+use std::ffi::OsString;
+use std::io::{self, IsTerminal, Read};
+use std::path::{Path, PathBuf};
 use std::process::Stdio;
 use std::sync::OnceLock;
+use std::time::{Duration, SystemTime};
+use std::{env, fmt, fs, process};
 
 use cargo_metadata::Message;
-use clap::{Args, Parser, Subcommand};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 
-use crate::{build_3dsx, build_smdh, cargo, get_metadata, link, print_command, CTRConfig};
+use crate::{
+    build_3dsx, build_smdh, cargo, cargo_config_value, check_smdh, expand_desc_suffix,
+    find_devices, get_metadata, get_post_build_command, git_hash, link, list_romfs,
+    print_artifact_message, print_command, print_config, print_size, run_post_build, simulate,
+    utc_date, CTRConfig, Cargo3dsMetadata, PreviousBuild, TARGET,
+};
 
 #[derive(Parser, Debug)]
 #[command(name = "cargo", bin_name = "cargo")]
@@ -15,22 +23,201 @@ pub enum Cargo {
     Input(Input),
 }
 
+impl Cargo {
+    /// Parse the command line like [`Parser::parse`], expanding the subcommand
+    /// if it is an alias from cargo's `[alias]` configuration. This way, aliases
+    /// of `build`, `run` and `test` get the same handling as the commands themselves.
+    pub fn parse_with_aliases() -> Self {
+        let args: Vec<OsString> = env::args_os().collect();
+        let cargo = Self::parse_from(&args);
+
+        let Self::Input(input) = &cargo;
+        match expand_alias(&args, &input.cmd, |alias| cargo_alias(&input.config, alias)) {
+            Some(args) => Self::parse_from(args),
+            None => cargo,
+        }
+    }
+}
+
+/// Cargo's built-in commands and their aliases, which `[alias]` can't redefine.
+const CARGO_COMMANDS: &[&str] = &[
+    "add",
+    "b",
+    "bench",
+    "build",
+    "c",
+    "check",
+    "clean",
+    "config",
+    "d",
+    "doc",
+    "fetch",
+    "fix",
+    "generate-lockfile",
+    "git-checkout",
+    "help",
+    "info",
+    "init",
+    "install",
+    "locate-project",
+    "login",
+    "logout",
+    "metadata",
+    "new",
+    "owner",
+    "package",
+    "pkgid",
+    "publish",
+    "r",
+    "read-manifest",
+    "remove",
+    "report",
+    "rm",
+    "run",
+    "rustc",
+    "rustdoc",
+    "search",
+    "t",
+    "test",
+    "tree",
+    "uninstall",
+    "update",
+    "vendor",
+    "verify-project",
+    "version",
+    "yank",
+];
+
+/// Replace the external subcommand of `cmd` in `args` with its expansion,
+/// if it isn't a cargo command and `lookup` finds it to be an alias.
+fn expand_alias(
+    args: &[OsString],
+    cmd: &CargoCmd,
+    lookup: impl FnOnce(&str) -> Option<Vec<String>>,
+) -> Option<Vec<OsString>> {
+    let CargoCmd::Passthrough(passthrough) = cmd else {
+        return None;
+    };
+    let alias = passthrough.first()?;
+    if CARGO_COMMANDS.contains(&alias.as_str()) {
+        return None;
+    }
+    let expansion = lookup(alias)?;
+
+    // Aliases can't shadow cargo commands, but avoid expanding forever anyway
+    if expansion.first() == Some(alias) {
+        return None;
+    }
+
+    // The external subcommand and its arguments are always last
+    let position = args.len() - passthrough.len();
+    let mut expanded = args[..position].to_vec();
+    expanded.extend(expansion.into_iter().map(OsString::from));
+    expanded.extend_from_slice(&args[position + 1..]);
+
+    Some(expanded)
+}
+
+/// Look up `alias` in cargo's `[alias]` configuration.
+fn cargo_alias(config: &[String], alias: &str) -> Option<Vec<String>> {
+    match cargo_config_value(config, &format!("alias.{alias}"))? {
+        toml::Value::String(expansion) => {
+            Some(expansion.split_whitespace().map(String::from).collect())
+        }
+        toml::Value::Array(expansion) => expansion
+            .iter()
+            .map(|arg| arg.as_str().map(String::from))
+            .collect(),
+        _ => None,
+    }
+}
+
 #[derive(Args, Debug)]
 #[command(version, about)]
 pub struct Input {
     #[command(subcommand)]
     pub cmd: CargoCmd,
 
-    /// Print the exact commands `cargo-3ds` is running. Note that this does not
-    /// set the verbose flag for cargo itself. To set cargo's verbosity flag, add
-    /// `-- -v` to the end of the command line.
+    /// Print the exact commands `cargo-3ds` is running, the path of the built
+    /// `.elf`, whether `build-std` is used, and the backtrace of unexpected
+    /// errors. Note that this does not set the verbose flag for cargo itself.
+    /// To set cargo's verbosity flag, add `-- -v` to the end of the command line.
     #[arg(long, short = 'v', global = true)]
     pub verbose: bool,
 
+    /// Don't print `cargo-3ds`'s status messages, only warnings and errors.
+    /// This also passes `--quiet` to cargo.
+    #[arg(long, short = 'q', global = true)]
+    pub quiet: bool,
+
     /// Set cargo configuration on the command line. This is equivalent to
     /// cargo's `--config` option.
     #[arg(long, global = true)]
     pub config: Vec<String>,
+
+    /// Coloring of cargo's output. This is equivalent to cargo's `--color` option.
+    ///
+    /// With `never`, escape codes are also removed from the output of executables
+    /// run with `3dslink --server`. `cargo-3ds`'s own messages are never colored.
+    #[arg(long, global = true, value_name = "WHEN", default_value_t = ColorChoice::Auto)]
+    pub color: ColorChoice,
+
+    /// Fail if no pre-built std is found for the 3DS target, instead of
+    /// building it with `-Z build-std`.
+    #[arg(long, global = true)]
+    pub no_build_std: bool,
+
+    /// Unstable (nightly-only) flags to pass to cargo, like cargo's `-Z` option.
+    /// If `-Z build-std` is passed, it is used instead of the automatic
+    /// `-Z build-std=std,test`.
+    #[arg(short = 'Z', global = true, value_name = "FLAG")]
+    pub unstable_flags: Vec<String>,
+}
+
+/// Whether to color output, like cargo's `--color` option.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorChoice {
+    /// Color output if printing to a terminal.
+    Auto,
+    /// Always color output.
+    Always,
+    /// Never color output.
+    Never,
+}
+
+impl Input {
+    /// Check that `--quiet` and `--verbose` weren't both passed. Since they
+    /// can be passed before or after the subcommand, clap can't check this.
+    pub fn check_verbosity(&self) -> Result<(), String> {
+        if self.quiet && self.verbose {
+            Err(String::from(
+                "error: `--quiet` and `--verbose` cannot be used together",
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Whether `-Z build-std` was passed explicitly, either to `cargo-3ds`
+    /// or in the args passed through to cargo. Executable arguments, and
+    /// anything else after `--`, are not cargo's own options.
+    pub fn has_build_std(&self) -> bool {
+        let is_build_std = |flag: &str| flag == "build-std" || flag.starts_with("build-std=");
+
+        let cargo_args = self.cmd.cargo_args();
+        let mut cargo_args = cargo_args.iter().take_while(|arg| *arg != "--");
+        let mut passed_to_cargo = false;
+        while let Some(arg) = cargo_args.next() {
+            let flag = match arg.strip_prefix("-Z") {
+                Some("") => cargo_args.next().map_or("", String::as_str),
+                Some(flag) => flag,
+                None => continue,
+            };
+            passed_to_cargo |= is_build_std(flag);
+        }
+
+        passed_to_cargo || self.unstable_flags.iter().any(|flag| is_build_std(flag))
+    }
 }
 
 /// Run a cargo command. COMMAND will be forwarded to the real
@@ -42,20 +229,37 @@ pub struct Input {
 #[command(allow_external_subcommands = true)]
 pub enum CargoCmd {
     /// Builds an executable suitable to run on a 3DS (3dsx).
+    #[command(visible_alias = "b")]
     Build(Build),
 
     /// Builds an executable and sends it to a device with `3dslink`.
+    #[command(visible_alias = "r")]
     Run(Run),
 
     /// Builds a test executable and sends it to a device with `3dslink`.
     ///
     /// This can be used with `--test` for integration tests, or `--lib` for
     /// unit tests (which require a custom test runner).
+    #[command(visible_alias = "t")]
     Test(Test),
 
+    /// Builds a benchmark executable and sends it to a device with `3dslink`.
+    ///
+    /// The executable is run with `--bench`, like `cargo bench` does, followed
+    /// by the executable arguments (e.g. a filter).
+    Bench(Bench),
+
+    /// Builds an executable and copies it to the `3ds` directory of an SD card.
+    Install(Install),
+
     /// Sets up a new cargo project suitable to run on a 3DS.
     New(New),
 
+    /// Builds an executable and reports the size of its sections.
+    ///
+    /// This uses `arm-none-eabi-size` from devkitARM if it can be found.
+    Size(Size),
+
     // NOTE: it seems docstring + name for external subcommands are not rendered
     // in help, but we might as well set them here in case a future version of clap
     // does include them in help text.
@@ -64,7 +268,23 @@ pub enum CargoCmd {
     Passthrough(Vec<String>),
 }
 
-#[derive(Args, Debug)]
+/// How an executable is run after being built by `cargo 3ds run` or `cargo 3ds test`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RunMode {
+    /// The executable is not run.
+    NoRun,
+    /// `cargo-3ds` sends the built `.3dsx` to the device with `3dslink`,
+    /// passing executable arguments with 3dslink's `--args`.
+    Link,
+    /// `cargo-3ds` runs the built `.3dsx` in a simulator on the host, with
+    /// `cargo 3ds test --simulator`.
+    Simulator,
+    /// `cargo run` or `cargo test` runs the executable using the configured
+    /// `target.armv6k-nintendo-3ds.runner`, passing executable arguments after `--`.
+    CustomRunner,
+}
+
+#[derive(Args, Debug, Clone)]
 pub struct RemainingArgs {
     /// Pass additional options through to the `cargo` command.
     ///
@@ -83,27 +303,200 @@ pub struct RemainingArgs {
     args: Vec<String>,
 }
 
-#[derive(Args, Debug)]
+#[derive(Args, Debug, Clone)]
 pub struct Build {
     #[arg(from_global)]
     pub verbose: bool,
 
+    #[arg(from_global)]
+    pub quiet: bool,
+
+    #[arg(from_global)]
+    config: Vec<String>,
+
+    #[arg(from_global)]
+    pub color: ColorChoice,
+
+    /// Number of parallel jobs to build with. This is equivalent to cargo's
+    /// `--jobs` option.
+    #[arg(long, short = 'j', value_name = "N", allow_negative_numbers = true)]
+    pub jobs: Option<i32>,
+
+    /// Directory for all generated artifacts, including the `.3dsx`. This is
+    /// equivalent to cargo's `--target-dir` option.
+    #[arg(long, value_name = "DIRECTORY")]
+    pub target_dir: Option<PathBuf>,
+
+    /// Path to the `Cargo.toml` of the package to build, which is also used to
+    /// find its metadata. This is equivalent to cargo's `--manifest-path` option.
+    #[arg(long, value_name = "PATH")]
+    pub manifest_path: Option<PathBuf>,
+
+    /// Build with the given profile. This is equivalent to cargo's `--profile`
+    /// option.
+    #[arg(long, value_name = "PROFILE-NAME")]
+    pub profile: Option<String>,
+
+    /// Build with each of the given comma separated profiles in turn, e.g.
+    /// `--profiles debug,release`. `debug` is cargo's `dev` profile. This is
+    /// only supported by `cargo 3ds build`.
+    #[arg(
+        long,
+        value_name = "PROFILE-NAMES",
+        value_delimiter = ',',
+        conflicts_with = "profile"
+    )]
+    pub profiles: Vec<String>,
+
+    /// With `--profiles`, keep building the other profiles if cargo fails for
+    /// one, then report which profiles failed and exit with an error.
+    #[arg(long, requires = "profiles")]
+    pub keep_going: bool,
+
+    /// Space or comma separated list of features to activate. This is
+    /// equivalent to cargo's `--features` option.
+    #[arg(long, short = 'F', value_name = "FEATURES")]
+    pub features: Vec<String>,
+
+    /// Activate all available features. This is equivalent to cargo's
+    /// `--all-features` option.
+    #[arg(long)]
+    pub all_features: bool,
+
+    /// Do not activate the `default` feature. This is equivalent to cargo's
+    /// `--no-default-features` option.
+    #[arg(long)]
+    pub no_default_features: bool,
+
+    /// After building, print the path of the built `.3dsx` to stdout. All other
+    /// `cargo-3ds` output goes to stderr, so this can be used in scripts.
+    #[arg(long)]
+    pub print_3dsx_path: bool,
+
+    /// A command to run with the system shell after the `.3dsx` is built.
+    /// Overrides `post_build` in `[package.metadata.cargo-3ds]`.
+    ///
+    /// The paths of the built files are available in the `CARGO_3DS_3DSX`,
+    /// `CARGO_3DS_SMDH` and `CARGO_3DS_ELF` environment variables.
+    #[arg(long, value_name = "COMMAND")]
+    pub post_build: Option<String>,
+
+    /// Override the application name shown in the HOME menu.
+    #[arg(long, value_name = "NAME")]
+    pub app_name: Option<String>,
+
+    /// Override the application description shown in the HOME menu.
+    #[arg(long, value_name = "DESCRIPTION")]
+    pub app_desc: Option<String>,
+
+    /// Override the application author shown in the HOME menu.
+    #[arg(long, value_name = "AUTHOR")]
+    pub app_author: Option<String>,
+
+    /// Name the built `.3dsx` and `.smdh` files `NAME.3dsx` and `NAME.smdh`
+    /// instead of after the executable. Unlike `--app-name`, this doesn't
+    /// change the name shown in the HOME menu.
+    #[arg(long, value_name = "NAME", value_parser = parse_output_name)]
+    pub output_name: Option<String>,
+
+    /// Don't build an SMDH or embed it in the `.3dsx`. The resulting `.3dsx`
+    /// will have no name, description or icon in the HOME menu.
+    #[arg(long)]
+    pub no_smdh: bool,
+
+    /// Only build the SMDH of the executable, without building a `.3dsx`. The
+    /// executable is still compiled, since the SMDH's default name comes from
+    /// it. Only supported by `cargo 3ds build`.
+    #[arg(long, conflicts_with = "no_smdh")]
+    pub smdh_only: bool,
+
+    /// Don't build a `.3dsx`, so `3dsxtool` isn't needed, e.g. to only use the
+    /// `.elf` and the SMDH with other tools. With `--no-smdh`, only the `.elf`
+    /// is built. Only supported by `cargo 3ds build`.
+    #[arg(long)]
+    pub no_3dsx: bool,
+
+    /// Embed an existing SMDH file instead of building one from the package's
+    /// metadata and icon.
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["no_smdh", "smdh_only"])]
+    pub smdh: Option<PathBuf>,
+
+    /// Print the name, author, description, icon and other settings found for
+    /// the executable, without building a `.3dsx`. Only supported by
+    /// `cargo 3ds build`.
+    #[arg(long)]
+    pub print_config: bool,
+
+    /// Print the files that would be embedded in the `RomFS`, with their sizes,
+    /// without building anything.
+    #[arg(long)]
+    pub list_romfs: bool,
+
+    /// Whether a JSON `--message-format` was passed to cargo, in which case
+    /// a message describing the built files is printed too.
+    #[arg(skip)]
+    pub json_messages: bool,
+
+    /// Append the package version to the application description shown in
+    /// the HOME menu. A warning is printed if the version doesn't fit in a 3DS
+    /// title version, where the major, minor and patch versions take 6, 6 and
+    /// 4 bits.
+    #[arg(long)]
+    pub stamp_version: bool,
+
+    /// Append text to the application description shown in the HOME menu,
+    /// like `"build {date} {git_hash}"`. `{date}` is replaced with the current
+    /// UTC date, and `{git_hash}` with the commit of the package, if any.
+    #[arg(long, value_name = "SUFFIX")]
+    pub desc_suffix: Option<String>,
+
+    /// Treat problems found while building the `.3dsx` as errors instead of
+    /// warnings, like a `RomFS` larger than `romfs_size_limit`.
+    #[arg(long)]
+    pub strict: bool,
+
+    /// Fail if the package has no `icon.png`, instead of using libctru's
+    /// default icon.
+    #[arg(long)]
+    pub require_icon: bool,
+
+    /// Compress the `RomFS`, if the installed 3dsxtool supports it. This can
+    /// also be enabled with `romfs_compress` in `[package.metadata.cargo-3ds]`.
+    #[arg(long)]
+    pub romfs_compress: bool,
+
+    /// Optimize the code for the given CPU. This is equivalent to rustc's
+    /// `-C target-cpu` option.
+    ///
+    /// The 3DS has an ARM11 MPCore, so the only sensible values are `mpcore`
+    /// (the default for the target) and other ARMv6K CPUs.
+    #[arg(long, value_name = "CPU")]
+    pub target_cpu: Option<String>,
+
+    /// Pass a codegen option to rustc, like `opt-level=s`. This is equivalent
+    /// to rustc's `-C` option, and can be given multiple times.
+    #[arg(long, value_name = "OPT[=VALUE]")]
+    pub rustc_codegen: Vec<String>,
+
     // Passthrough cargo options.
     #[command(flatten)]
     pub passthrough: RemainingArgs,
 }
 
-#[derive(Args, Debug)]
+#[derive(Args, Debug, Clone)]
 pub struct Run {
-    /// Specify the IP address of the device to send the executable to.
+    /// Specify the IP address or hostname of the device to send the executable to.
     ///
     /// Corresponds to 3dslink's `--address` arg, which defaults to automatically
     /// finding the device.
-    #[arg(long, short = 'a')]
-    pub address: Option<std::net::Ipv4Addr>,
+    #[arg(long, short = 'a', value_parser = parse_address)]
+    pub address: Option<String>,
 
     /// Set the 0th argument of the executable when running it. Corresponds to
     /// 3dslink's `--argv0` argument.
+    ///
+    /// `{name}` is replaced with the application name, e.g.
+    /// `sdmc:/3ds/{name}/{name}.3dsx`.
     #[arg(long, short = '0')]
     pub argv0: Option<String>,
 
@@ -118,12 +511,45 @@ pub struct Run {
     #[arg(long)]
     pub retries: Option<usize>,
 
+    /// Pass an argument to `3dslink` as is, e.g. for options of newer versions
+    /// of 3dslink. This can be repeated, and the arguments are added before the
+    /// executable's arguments.
+    #[arg(long = "3dslink-arg", value_name = "ARG", allow_hyphen_values = true)]
+    pub extra_link_args: Vec<String>,
+
+    /// Show the output of the executable sent over the network until Ctrl-C is
+    /// pressed, then exit successfully. This implies `--server` and
+    /// `--no-custom-runner`.
+    #[arg(long)]
+    pub stdio_over_network: bool,
+
+    /// Send the executable with `3dslink` even if Cargo's
+    /// `target.armv6k-nintendo-3ds.runner` is configured.
+    #[arg(long)]
+    pub no_custom_runner: bool,
+
+    /// Send the previously built `.3dsx` without building it again.
+    /// This implies `--no-custom-runner`, and is only supported by `cargo 3ds run`.
+    #[arg(long)]
+    pub no_build: bool,
+
+    /// List the devices found on the network which are ready to receive an
+    /// executable, without building or sending anything.
+    #[arg(long)]
+    pub list_devices: bool,
+
+    /// Only send the `.3dsx` given with `--3dsx` to the device, without
+    /// building anything. This can be used with files built by other tools.
+    #[arg(long, requires = "path_3dsx")]
+    pub link_only: bool,
+
+    /// The `.3dsx` to send with `--link-only`.
+    #[arg(long = "3dsx", value_name = "PATH", requires = "link_only")]
+    pub path_3dsx: Option<PathBuf>,
+
     // Passthrough `cargo build` options.
     #[command(flatten)]
     pub build_args: Build,
-
-    #[arg(from_global)]
-    config: Vec<String>,
 }
 
 #[derive(Args, Debug)]
@@ -138,11 +564,57 @@ pub struct Test {
     #[arg(long)]
     pub doc: bool,
 
+    /// Run the tests in a simulator instead of sending them to a device, and
+    /// fail if they fail. The simulator is run with the path of the `.3dsx`,
+    /// and defaults to the `CARGO_3DS_SIMULATOR` environment variable.
+    #[arg(
+        long,
+        value_name = "PROGRAM",
+        num_args = 0..=1,
+        require_equals = true,
+        conflicts_with_all = ["no_run", "doc"]
+    )]
+    pub simulator: Option<Option<String>>,
+
+    /// Stop the simulator if it is still running after this many seconds.
+    #[arg(long, value_name = "SECONDS", requires = "simulator")]
+    pub simulator_timeout: Option<u64>,
+
     // The test command uses a superset of the same arguments as Run.
     #[command(flatten)]
     pub run_args: Run,
 }
 
+#[derive(Args, Debug)]
+pub struct Bench {
+    /// If set, the built executable will not be sent to the device to run it.
+    #[arg(long)]
+    pub no_run: bool,
+
+    // The bench command uses the same arguments as Run.
+    #[command(flatten)]
+    pub run_args: Run,
+}
+
+#[derive(Args, Debug)]
+pub struct Size {
+    // Passthrough `cargo build` options.
+    #[command(flatten)]
+    pub build_args: Build,
+}
+
+#[derive(Args, Debug)]
+pub struct Install {
+    /// Path where the SD card is mounted. Defaults to the `CARGO_3DS_SD`
+    /// environment variable.
+    #[arg(long, value_name = "PATH")]
+    pub sd: Option<PathBuf>,
+
+    // Passthrough `cargo build` options.
+    #[command(flatten)]
+    pub build_args: Build,
+}
+
 #[derive(Args, Debug)]
 pub struct New {
     /// Path of the new project.
@@ -161,6 +633,9 @@ impl CargoCmd {
             CargoCmd::Build(build) => build.passthrough.cargo_args(),
             CargoCmd::Run(run) => run.build_args.passthrough.cargo_args(),
             CargoCmd::Test(test) => test.cargo_args(),
+            CargoCmd::Bench(bench) => bench.cargo_args(),
+            CargoCmd::Size(size) => size.build_args.passthrough.cargo_args(),
+            CargoCmd::Install(install) => install.build_args.passthrough.cargo_args(),
             CargoCmd::New(new) => {
                 // We push the original path in the new command (we captured it in [`New`] to learn about the context)
                 let mut cargo_args = new.cargo_args.cargo_args();
@@ -181,35 +656,90 @@ impl CargoCmd {
     /// if no custom runner is configured).
     pub fn subcommand_name(&self) -> &str {
         match self {
-            CargoCmd::Build(_) => "build",
-            CargoCmd::Run(run) => {
-                if run.use_custom_runner() {
+            CargoCmd::Build(_) | CargoCmd::Size(_) | CargoCmd::Install(_) => "build",
+            CargoCmd::Run(_) => {
+                if self.run_mode() == RunMode::CustomRunner {
                     "run"
                 } else {
                     "build"
                 }
             }
             CargoCmd::Test(_) => "test",
+            CargoCmd::Bench(_) => "bench",
             CargoCmd::New(_) => "new",
             CargoCmd::Passthrough(cmd) => &cmd[0],
         }
     }
 
+    /// Returns the build options of commands that build an executable.
+    pub fn build_args(&self) -> Option<&Build> {
+        match self {
+            Self::Build(build) => Some(build),
+            Self::Run(run)
+            | Self::Test(Test { run_args: run, .. })
+            | Self::Bench(Bench { run_args: run, .. }) => Some(&run.build_args),
+            Self::Size(size) => Some(&size.build_args),
+            Self::Install(install) => Some(&install.build_args),
+            Self::New(_) | Self::Passthrough(_) => None,
+        }
+    }
+
+    fn build_args_mut(&mut self) -> Option<&mut Build> {
+        match self {
+            Self::Build(build) => Some(build),
+            Self::Run(run)
+            | Self::Test(Test { run_args: run, .. })
+            | Self::Bench(Bench { run_args: run, .. }) => Some(&mut run.build_args),
+            Self::Size(size) => Some(&mut size.build_args),
+            Self::Install(install) => Some(&mut install.build_args),
+            Self::New(_) | Self::Passthrough(_) => None,
+        }
+    }
+
+    /// Whether or not the cargo command should be run at all, or if the
+    /// callback should only use previously built files.
+    pub fn should_run_cargo(&self) -> bool {
+        if self.build_args().is_some_and(|build| build.list_romfs) {
+            return false;
+        }
+
+        self.run_args()
+            .is_none_or(|run| !run.no_build && !run.list_devices && !run.link_only)
+    }
+
+    /// Returns the options for running the executable, for commands that run it.
+    pub fn run_args(&self) -> Option<&Run> {
+        match self {
+            Self::Run(run)
+            | Self::Test(Test { run_args: run, .. })
+            | Self::Bench(Bench { run_args: run, .. }) => Some(run),
+            _ => None,
+        }
+    }
+
     /// Whether or not this command should compile any code, and thus needs import the custom environment configuration (e.g. target spec).
     pub fn should_compile(&self) -> bool {
         matches!(
             self,
-            Self::Build(_) | Self::Run(_) | Self::Test(_) | Self::Passthrough(_)
+            Self::Build(_)
+                | Self::Run(_)
+                | Self::Test(_)
+                | Self::Bench(_)
+                | Self::Size(_)
+                | Self::Install(_)
+                | Self::Passthrough(_)
         )
     }
 
     /// Whether or not this command should build a 3DSX executable file.
     pub fn should_build_3dsx(&self) -> bool {
         match self {
-            Self::Build(_) | CargoCmd::Run(_) => true,
-            &Self::Test(Test { doc, .. }) => {
-                if doc {
-                    eprintln!("Documentation tests requested, no 3dsx will be built");
+            Self::Build(_) | CargoCmd::Run(_) | CargoCmd::Bench(_) | CargoCmd::Install(_) => true,
+            Self::Test(Test { doc, run_args, .. }) => {
+                if *doc {
+                    if !run_args.build_args.quiet {
+                        eprintln!("Documentation tests requested, no 3dsx will be built");
+                    }
                     false
                 } else {
                     true
@@ -219,28 +749,72 @@ impl CargoCmd {
         }
     }
 
+    /// Whether or not this command needs the [`CTRConfig`] of the built executable.
+    pub fn should_get_metadata(&self) -> bool {
+        if self
+            .run_args()
+            .is_some_and(|run| run.list_devices || run.link_only)
+            || self.build_args().is_some_and(|build| build.list_romfs)
+        {
+            return false;
+        }
+
+        matches!(self, Self::Size(_)) || self.should_build_3dsx()
+    }
+
     /// Whether or not the resulting executable should be sent to the 3DS with
     /// `3dslink`.
     pub fn should_link_to_device(&self) -> bool {
+        self.run_mode() == RunMode::Link
+    }
+
+    /// How the executable built by this command will be run, if at all.
+    pub fn run_mode(&self) -> RunMode {
+        self.run_mode_with(Run::custom_runner_configured)
+    }
+
+    /// Like [`CargoCmd::run_mode`], using `custom_runner` to determine whether
+    /// `target.armv6k-nintendo-3ds.runner` is configured.
+    fn run_mode_with(&self, custom_runner: impl FnOnce(&Run) -> bool) -> RunMode {
         match self {
-            Self::Test(Test { no_run: true, .. }) => false,
-            Self::Run(run) | Self::Test(Test { run_args: run, .. }) => !run.use_custom_runner(),
-            _ => false,
+            Self::Test(Test { no_run: true, .. }) | Self::Bench(Bench { no_run: true, .. }) => {
+                RunMode::NoRun
+            }
+            Self::Test(Test {
+                simulator: Some(_), ..
+            }) => RunMode::Simulator,
+            // Doctests can only be run by a custom runner, there is no 3dsx to link
+            Self::Test(Test {
+                doc: true,
+                run_args,
+                ..
+            }) => {
+                if run_args.use_custom_runner_with(custom_runner) {
+                    RunMode::CustomRunner
+                } else {
+                    RunMode::NoRun
+                }
+            }
+            Self::Run(run)
+            | Self::Test(Test { run_args: run, .. })
+            | Self::Bench(Bench { run_args: run, .. }) => {
+                if run.use_custom_runner_with(custom_runner) {
+                    RunMode::CustomRunner
+                } else {
+                    RunMode::Link
+                }
+            }
+            _ => RunMode::NoRun,
         }
     }
 
     pub const DEFAULT_MESSAGE_FORMAT: &'static str = "json-render-diagnostics";
 
     pub fn extract_message_format(&mut self) -> Result<Option<String>, String> {
-        let cargo_args = match self {
-            Self::Build(build) => &mut build.passthrough.args,
-            Self::Run(run) => &mut run.build_args.passthrough.args,
-            Self::New(new) => &mut new.cargo_args.args,
-            Self::Test(test) => &mut test.run_args.build_args.passthrough.args,
-            Self::Passthrough(args) => args,
-        };
-
-        let format = Self::extract_message_format_from_args(cargo_args)?;
+        let format = Self::extract_message_format_from_args(self.passthrough_args_mut())?;
+        if let Some(build) = self.build_args_mut() {
+            build.json_messages = format.is_some();
+        }
         if format.is_some() {
             return Ok(format);
         }
@@ -255,11 +829,27 @@ impl CargoCmd {
         }
     }
 
+    /// The unparsed arguments of this command, including executable args.
+    fn passthrough_args_mut(&mut self) -> &mut Vec<String> {
+        match self {
+            Self::Build(build) => &mut build.passthrough.args,
+            Self::Run(run) => &mut run.build_args.passthrough.args,
+            Self::Size(size) => &mut size.build_args.passthrough.args,
+            Self::Install(install) => &mut install.build_args.passthrough.args,
+            Self::New(new) => &mut new.cargo_args.args,
+            Self::Test(test) => &mut test.run_args.build_args.passthrough.args,
+            Self::Bench(bench) => &mut bench.run_args.build_args.passthrough.args,
+            Self::Passthrough(args) => args,
+        }
+    }
+
     fn extract_message_format_from_args(
         cargo_args: &mut Vec<String>,
     ) -> Result<Option<String>, String> {
-        // Checks for a position within the args where '--message-format' is located
-        if let Some(pos) = cargo_args
+        let mut message_format: Option<String> = None;
+
+        // Checks for every position within the args where '--message-format' is located
+        while let Some(pos) = cargo_args
             .iter()
             .position(|s| s.starts_with("--message-format"))
         {
@@ -271,21 +861,147 @@ impl CargoCmd {
             // otherwise remove next arg which is now at the same position as the original flag.
             let format = if let Some((_, format)) = arg.split_once('=') {
                 format.to_string()
-            } else {
+            } else if pos < cargo_args.len() {
                 // Also need to remove the argument to the --message-format option
                 cargo_args.remove(pos)
+            } else {
+                return Err(String::from("error: `--message-format` requires a value"));
             };
 
             // Non-json formats are not supported so the executable exits.
-            if format.starts_with("json") {
-                Ok(Some(format))
-            } else {
-                Err(String::from(
+            // Cargo accepts comma separated values, which must all be JSON ones.
+            if !format.split(',').all(|format| format.starts_with("json")) {
+                return Err(String::from(
                     "error: non-JSON `message-format` is not supported",
-                ))
+                ));
+            }
+
+            match &message_format {
+                Some(previous) if *previous != format => {
+                    return Err(format!(
+                        "error: conflicting `message-format` values `{previous}` and `{format}`"
+                    ));
+                }
+                _ => message_format = Some(format),
+            }
+        }
+
+        Ok(message_format)
+    }
+
+    /// Check that no `--target` other than the 3DS target was passed to cargo.
+    /// `cargo-3ds` always builds for `armv6k-nintendo-3ds`, so another target
+    /// is rejected rather than building for both.
+    pub fn check_target(&self) -> Result<(), String> {
+        if !self.should_compile() {
+            return Ok(());
+        }
+
+        let cargo_args = self.cargo_args();
+        let other_target = option_values(&cargo_args, "--target").find(|target| *target != TARGET);
+
+        match other_target {
+            Some(target) => Err(format!(
+                "error: cannot build for `{target}`, cargo 3ds always targets {TARGET}"
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Check that the options shared by several subcommands are only used by
+    /// the ones they work with.
+    pub fn check_options(&self) -> Result<(), String> {
+        // Test and benchmark executables are named with a hash, which
+        // `--no-build` can't find without cargo
+        let test_or_bench = match self {
+            Self::Test(Test { run_args, .. }) => Some(("test", run_args)),
+            Self::Bench(Bench { run_args, .. }) => Some(("bench", run_args)),
+            _ => None,
+        };
+        if let Some((name, _)) = test_or_bench.filter(|(_, run)| run.no_build) {
+            return Err(format!(
+                "error: `--no-build` can only be used with `cargo 3ds run`, not `cargo 3ds {name}`"
+            ));
+        }
+
+        if let Self::Test(
+            test @ Test {
+                simulator: Some(_), ..
+            },
+        ) = self
+        {
+            // The simulator is only given the `.3dsx`, it has no way to pass
+            // arguments to the tests
+            if !test.run_args.build_args.passthrough.exe_args().is_empty() {
+                return Err(String::from(
+                    "error: executable arguments cannot be passed to the tests with `--simulator`",
+                ));
             }
+            if test
+                .simulator_program(env::var(SIMULATOR_VAR).ok())
+                .is_none()
+            {
+                return Err(String::from(
+                    "error: no simulator configured, pass one with `--simulator=PROGRAM` \
+                    or set CARGO_3DS_SIMULATOR",
+                ));
+            }
+        }
+
+        // These skip the `.3dsx`, which every other command runs, installs or measures
+        if let Some(build) = self
+            .build_args()
+            .filter(|_| !matches!(self, Self::Build(_)))
+        {
+            for (set, flag) in [
+                (build.no_3dsx, "--no-3dsx"),
+                (build.smdh_only, "--smdh-only"),
+                (build.print_config, "--print-config"),
+            ] {
+                if set {
+                    return Err(format!(
+                        "error: `{flag}` can only be used with `cargo 3ds build`"
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that `--profiles` is only used by `cargo 3ds build`, and without
+    /// another profile passed to cargo.
+    pub fn check_profiles(&self) -> Result<(), String> {
+        let Some(build) = self.build_args().filter(|build| !build.profiles.is_empty()) else {
+            return Ok(());
+        };
+
+        if !matches!(self, Self::Build(_)) {
+            Err(String::from(
+                "error: `--profiles` can only be used with `cargo 3ds build`",
+            ))
+        } else if build.selected_profile().is_some() {
+            Err(String::from(
+                "error: `--profiles` cannot be used together with `--release` or `--profile`",
+            ))
         } else {
-            Ok(None)
+            Ok(())
+        }
+    }
+
+    /// Remove the profiles passed with `--profiles`, to build with each of
+    /// them using [`Self::set_profile`].
+    pub fn take_profiles(&mut self) -> Vec<String> {
+        self.build_args_mut()
+            .map(|build| std::mem::take(&mut build.profiles))
+            .unwrap_or_default()
+    }
+
+    /// Build with `profile`, like with `--profile`. `debug` is accepted for
+    /// cargo's `dev` profile, since that is the name of its output directory.
+    pub fn set_profile(&mut self, profile: &str) {
+        if let Some(build) = self.build_args_mut() {
+            let profile = if profile == "debug" { "dev" } else { profile };
+            build.profile = Some(profile.to_string());
         }
     }
 
@@ -295,12 +1011,50 @@ impl CargoCmd {
     ///
     /// - `cargo 3ds build` and other "build" commands will use their callbacks to build the final `.3dsx` file and link it.
     /// - `cargo 3ds new` and other generic commands will use their callbacks to make 3ds-specific changes to the environment.
-    pub fn run_callback(&self, messages: &[Message]) {
+    ///
+    /// Returns the config of the built package, if metadata was used.
+    pub fn run_callback(&self, messages: &[Message]) -> Option<CTRConfig> {
+        // The RomFS only depends on the package, so nothing was built
+        if let Some(build) = self.build_args().filter(|build| build.list_romfs) {
+            list_romfs(build);
+            return None;
+        }
+
         // Process the metadata only for commands that have it/use it
-        let config = if self.should_build_3dsx() {
-            eprintln!("Getting metadata");
+        let config = if self.should_get_metadata() {
+            if !self.build_args().is_some_and(|build| build.quiet) {
+                eprintln!("Getting metadata");
+            }
+
+            let metadata_args = self
+                .build_args()
+                .map(Build::metadata_args)
+                .unwrap_or_default();
+
+            let target = self.build_args().and_then(Build::selected_target);
+            let previous = self
+                .run_args()
+                .filter(|run| run.no_build)
+                .map(|run| run.build_args.previous_build());
+            let mut config = get_metadata(
+                messages,
+                &metadata_args,
+                target.as_deref(),
+                previous.as_ref(),
+            );
+            if let Some(build) = self.build_args() {
+                build.override_config(&mut config);
+
+                if build.require_icon && config.has_default_icon() {
+                    eprintln!(
+                        "No icon.png found for {}, and `--require-icon` was passed",
+                        config.cargo_manifest_path().display()
+                    );
+                    process::exit(1);
+                }
+            }
 
-            Some(get_metadata(messages))
+            Some(config)
         } else {
             None
         };
@@ -310,12 +1064,77 @@ impl CargoCmd {
             Self::Build(cmd) => cmd.callback(&config),
             Self::Run(cmd) => cmd.callback(&config),
             Self::Test(cmd) => cmd.callback(&config),
+            Self::Bench(cmd) => cmd.callback(&config),
+            Self::Size(cmd) => cmd.callback(&config),
+            Self::Install(cmd) => cmd.callback(&config),
             Self::New(cmd) => cmd.callback(),
             _ => (),
         }
+
+        config
+    }
+}
+
+/// Validate a device address. Hostnames are resolved by `3dslink` itself, so
+/// this only rejects values that can't possibly be an address.
+fn parse_address(address: &str) -> Result<String, String> {
+    if address.is_empty() {
+        Err(String::from("address must not be empty"))
+    } else if address.starts_with('-') {
+        // 3dslink would parse this as one of its own options instead
+        Err(String::from("address must not start with `-`"))
+    } else if address.contains(char::is_whitespace) {
+        Err(String::from("address must not contain whitespace"))
+    } else {
+        Ok(address.to_string())
+    }
+}
+
+/// Validate an output file name, which is used next to the executable, so it
+/// can't contain a directory.
+fn parse_output_name(name: &str) -> Result<String, String> {
+    if name.is_empty() {
+        Err(String::from("output name must not be empty"))
+    } else if name.contains(['/', '\\']) || name == "." || name == ".." {
+        Err(String::from("output name must be a file name, not a path"))
+    } else {
+        Ok(name.to_string())
+    }
+}
+
+impl ColorChoice {
+    /// Resolve [`ColorChoice::Auto`] based on whether stderr is a terminal.
+    ///
+    /// Since `cargo-3ds` reads cargo's output through a pipe, cargo can't always
+    /// detect a terminal by itself, so an explicit choice is always forwarded.
+    pub fn resolve(self) -> Self {
+        match self {
+            Self::Auto if io::stderr().is_terminal() => Self::Always,
+            Self::Auto => Self::Never,
+            choice => choice,
+        }
+    }
+}
+
+impl fmt::Display for ColorChoice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let value = self.to_possible_value().expect("no skipped variants");
+        f.write_str(value.get_name())
     }
 }
 
+/// Find the values given to `option` in `args`, either as separate arguments
+/// or joined with `=`.
+fn option_values<'a>(args: &'a [String], option: &'a str) -> impl Iterator<Item = &'a str> {
+    args.iter().enumerate().filter_map(move |(i, arg)| {
+        if arg == option {
+            args.get(i + 1).map(String::as_str)
+        } else {
+            arg.strip_prefix(option)?.strip_prefix('=')
+        }
+    })
+}
+
 impl RemainingArgs {
     /// Get the args to be passed to `cargo`.
     pub fn cargo_args(&self) -> Vec<String> {
@@ -343,41 +1162,323 @@ impl RemainingArgs {
 }
 
 impl Build {
-    /// Callback for `cargo 3ds build`.
-    ///
-    /// This callback handles building the application as a `.3dsx` file.
-    fn callback(&self, config: &Option<CTRConfig>) {
-        if let Some(config) = config {
-            eprintln!("Building smdh: {}", config.path_smdh().display());
-            build_smdh(config);
+    /// Options to pass to `cargo metadata`, so it behaves consistently with
+    /// the build command regarding network access and configuration.
+    pub fn metadata_args(&self) -> Vec<String> {
+        let mut args: Vec<String> = self
+            .config
+            .iter()
+            .map(|cfg| format!("--config={cfg}"))
+            .collect();
 
-            eprintln!("Building 3dsx: {}", config.path_3dsx().display());
-            build_3dsx(config, self.verbose);
+        let cargo_args = self.passthrough.cargo_args();
+        args.extend(
+            cargo_args
+                .iter()
+                .filter(|arg| ["--offline", "--frozen", "--locked"].contains(&arg.as_str()))
+                .cloned(),
+        );
+
+        // Without the manifest of the build, `cargo metadata` would describe
+        // the package in the current directory instead.
+        if let Some(manifest_path) = self.selected_manifest_path() {
+            args.push(format!("--manifest-path={}", manifest_path.display()));
         }
-    }
-}
 
-impl Run {
-    /// Get the args to pass to `3dslink` based on these options.
-    pub fn get_3dslink_args(&self) -> Vec<String> {
+        // `cargo metadata` has no `--target-dir`, but reports the configured one.
+        // It may also have been passed along with other cargo options.
+        let target_dir = self
+            .target_dir
+            .as_ref()
+            .map(|dir| dir.to_string_lossy().into_owned())
+            .or_else(|| {
+                option_values(&cargo_args, "--target-dir")
+                    .next()
+                    .map(String::from)
+            });
+        if let Some(target_dir) = target_dir {
+            let target_dir = toml::Value::from(target_dir);
+            args.push(format!("--config=build.target-dir={target_dir}"));
+        }
+
+        args
+    }
+
+    /// The rustc flags for the codegen options passed with `--target-cpu` and
+    /// `--rustc-codegen`. These are added to the rustflags of the build.
+    pub fn codegen_flags(&self) -> Vec<String> {
+        self.target_cpu
+            .iter()
+            .map(|cpu| format!("-Ctarget-cpu={cpu}"))
+            .chain(self.rustc_codegen.iter().map(|opt| format!("-C{opt}")))
+            .collect()
+    }
+
+    /// The cargo profile selected for the build, if not the default. It may be
+    /// passed with `--profile` or `--release`, along with other cargo options.
+    pub fn selected_profile(&self) -> Option<String> {
+        if self.profile.is_some() {
+            return self.profile.clone();
+        }
+
+        let cargo_args = self.passthrough.cargo_args();
+        let profile = option_values(&cargo_args, "--profile")
+            .next()
+            .map(String::from);
+        profile.or_else(|| {
+            cargo_args
+                .iter()
+                .any(|arg| arg == "--release" || arg == "-r")
+                .then(|| String::from("release"))
+        })
+    }
+
+    /// The features requested with `--features`, either to `cargo-3ds` or in
+    /// the args passed to cargo, and `default` unless `--no-default-features`
+    /// was passed.
+    pub fn requested_features(&self) -> Vec<String> {
+        let cargo_args = self.passthrough.cargo_args();
+        let mut features: Vec<String> = self
+            .features
+            .iter()
+            .map(String::as_str)
+            .chain(option_values(&cargo_args, "--features"))
+            .flat_map(|features| features.split([' ', ',']))
+            .filter(|feature| !feature.is_empty())
+            .map(String::from)
+            .collect();
+
+        if !self.no_default_features && !cargo_args.iter().any(|arg| arg == "--no-default-features")
+        {
+            features.push(String::from("default"));
+        }
+        features
+    }
+
+    /// Whether the `.3dsx` isn't built, with `--smdh-only`, `--no-3dsx`,
+    /// `--print-config` or `--list-romfs`, so it can't be run or installed either.
+    pub fn skips_3dsx(&self) -> bool {
+        self.smdh_only || self.no_3dsx || self.print_config || self.list_romfs
+    }
+
+    /// Where to find the executable built by a previous invocation, to use it
+    /// with `--no-build`.
+    pub fn previous_build(&self) -> PreviousBuild {
+        PreviousBuild {
+            profile: self.selected_profile(),
+            package: self.selected_package_name(),
+            manifest_path: self.selected_manifest_path(),
+            features: self.requested_features(),
+        }
+    }
+
+    /// The manifest given with `--manifest-path`, either to `cargo-3ds` or in
+    /// the args passed to cargo.
+    pub fn selected_manifest_path(&self) -> Option<PathBuf> {
+        self.manifest_path.clone().or_else(|| {
+            option_values(&self.passthrough.cargo_args(), "--manifest-path")
+                .next()
+                .map(PathBuf::from)
+        })
+    }
+
+    /// The name of the package selected with `--package` in the args passed to cargo.
+    pub fn selected_package_name(&self) -> Option<String> {
+        let cargo_args = self.passthrough.cargo_args();
+        option_values(&cargo_args, "--package")
+            .chain(option_values(&cargo_args, "-p"))
+            .last()
+            .map(String::from)
+    }
+
+    /// The name of the example selected with `--example`, if any.
+    pub fn selected_example(&self) -> Option<String> {
+        option_values(&self.passthrough.cargo_args(), "--example")
+            .last()
+            .map(String::from)
+    }
+
+    /// The name of the binary or example selected with `--bin` or `--example`,
+    /// whose executable is used when more than one is built.
+    pub fn selected_target(&self) -> Option<String> {
+        let cargo_args = self.passthrough.cargo_args();
+        let bins = option_values(&cargo_args, "--bin");
+        let examples = option_values(&cargo_args, "--example");
+        bins.chain(examples).last().map(String::from)
+    }
+
+    /// Apply the metadata overrides passed on the command line to `config`.
+    fn override_config(&self, config: &mut CTRConfig) {
+        if let Some(name) = &self.app_name {
+            config.name = name.clone();
+        }
+        if let Some(description) = &self.app_desc {
+            config.description = description.clone();
+        }
+        if let Some(author) = &self.app_author {
+            config.author = author.clone();
+        }
+        if let Some(output_name) = &self.output_name {
+            config.output_name = Some(output_name.clone());
+        }
+        if self.stamp_version {
+            if let Some(version) = &config.version {
+                // A CIA's title version can't hold every package version
+                if let Err(e) = config.title_version() {
+                    eprintln!("warning: {e}");
+                }
+                config.description = format!("{} (v{version})", config.description);
+            }
+        }
+        if let Some(suffix) = &self.desc_suffix {
+            let package_dir = config
+                .cargo_manifest_path
+                .parent()
+                .filter(|dir| dir.is_dir())
+                .unwrap_or(Path::new("."));
+            let suffix = expand_desc_suffix(
+                suffix,
+                git_hash(package_dir).as_deref(),
+                &utc_date(SystemTime::now()),
+            );
+            config.description = format!("{} {suffix}", config.description);
+        }
+    }
+
+    /// Callback for `cargo 3ds build`.
+    ///
+    /// This callback handles building the application as a `.3dsx` file.
+    fn callback(&self, config: &Option<CTRConfig>) {
+        if let Some(config) = config {
+            if self.verbose {
+                eprintln!("Using elf: {}", config.target_path.display());
+            }
+
+            if self.print_config {
+                print_config(config);
+                return;
+            }
+
+            let smdh = if self.no_smdh {
+                None
+            } else if let Some(smdh) = &self.smdh {
+                if let Err(e) = check_smdh(smdh) {
+                    eprintln!("{e}");
+                    process::exit(1);
+                }
+                if !self.quiet {
+                    eprintln!("Using smdh: {}", smdh.display());
+                }
+                Some(smdh.clone())
+            } else {
+                if !self.quiet {
+                    eprintln!("Building smdh: {}", config.path_smdh().display());
+                }
+                build_smdh(config);
+                Some(config.path_smdh())
+            };
+
+            if self.smdh_only || self.no_3dsx {
+                return;
+            }
+
+            if !self.quiet {
+                eprintln!("Building 3dsx: {}", config.path_3dsx().display());
+            }
+            build_3dsx(config, smdh.as_deref(), self);
+
+            if self.json_messages {
+                print_artifact_message(config, smdh.as_deref());
+            }
+
+            let post_build = self
+                .post_build
+                .clone()
+                .or_else(|| get_post_build_command(config));
+            if let Some(post_build) = post_build {
+                if !self.quiet {
+                    eprintln!("Running post-build command");
+                }
+                run_post_build(config, &post_build, self.verbose);
+            }
+
+            if self.print_3dsx_path {
+                println!("{}", config.path_3dsx().display());
+            }
+        }
+    }
+}
+
+/// The config to send a `.3dsx` built elsewhere with `--link-only`, named after
+/// the file.
+fn link_only_config(path_3dsx: &Path) -> Result<CTRConfig, String> {
+    if path_3dsx
+        .extension()
+        .is_none_or(|extension| extension != "3dsx")
+    {
+        return Err(format!(
+            "error: {} is not a `.3dsx` file",
+            path_3dsx.display()
+        ));
+    }
+    if !path_3dsx.is_file() {
+        return Err(format!("error: {} does not exist", path_3dsx.display()));
+    }
+
+    let name = path_3dsx.file_stem().unwrap_or_default().to_string_lossy();
+    Ok(CTRConfig::builder()
+        .name(name)
+        .target_path(path_3dsx)
+        .build())
+}
+
+/// Environment variable with the device address to use when it isn't
+/// given with `--address` or in the package's settings.
+const ADDRESS_VAR: &str = "CARGO_3DS_ADDRESS";
+
+impl Run {
+    /// Get the args to pass to `3dslink` based on these options.
+    ///
+    /// The device address is taken from `--address`, then the `address`
+    /// setting of the package, then the `CARGO_3DS_ADDRESS` environment
+    /// variable. Without any of them `3dslink` looks for the device itself.
+    pub fn get_3dslink_args(&self, config: &CTRConfig, settings: &Cargo3dsMetadata) -> Vec<String> {
+        self.get_3dslink_args_with_env(config, settings, env::var(ADDRESS_VAR).ok())
+    }
+
+    /// Like [`Run::get_3dslink_args`], but with the value of
+    /// `CARGO_3DS_ADDRESS` given instead of read from the environment.
+    pub fn get_3dslink_args_with_env(
+        &self,
+        config: &CTRConfig,
+        settings: &Cargo3dsMetadata,
+        env_address: Option<String>,
+    ) -> Vec<String> {
         let mut args = Vec::new();
 
-        if let Some(address) = self.address {
-            args.extend(["--address".to_string(), address.to_string()]);
+        let address = self
+            .address
+            .clone()
+            .or_else(|| settings.address.clone())
+            .or_else(|| env_address.filter(|address| !address.is_empty()));
+        if let Some(address) = address {
+            args.extend(["--address".to_string(), address]);
         }
 
         if let Some(argv0) = &self.argv0 {
-            args.extend(["--arg0".to_string(), argv0.clone()]);
+            args.extend(["--arg0".to_string(), argv0.replace("{name}", config.name())]);
         }
 
         if let Some(retries) = self.retries {
             args.extend(["--retries".to_string(), retries.to_string()]);
         }
 
-        if self.server {
+        if self.server || self.stdio_over_network {
             args.push("--server".to_string());
         }
 
+        args.extend(self.extra_link_args.iter().cloned());
+
         let exe_args = self.build_args.passthrough.exe_args();
         if !exe_args.is_empty() {
             // For some reason 3dslink seems to want 2 instances of `--`, one
@@ -403,12 +1504,55 @@ impl Run {
     ///
     /// This callback handles launching the application via `3dslink`.
     fn callback(&self, config: &Option<CTRConfig>) {
-        // Run the normal "build" callback
-        self.build_args.callback(config);
+        if self.list_devices {
+            if !self.build_args.quiet {
+                eprintln!("Looking for devices...");
+            }
+            let devices = find_devices(Duration::from_secs(2)).unwrap_or_else(|e| {
+                eprintln!("Could not look for devices: {e}");
+                process::exit(1);
+            });
 
-        if !self.use_custom_runner() {
-            if let Some(cfg) = config {
+            if devices.is_empty() {
+                eprintln!("No devices found");
+            }
+            for device in devices {
+                println!("{device}");
+            }
+            return;
+        }
+
+        if let Some(path_3dsx) = self.path_3dsx.as_deref().filter(|_| self.link_only) {
+            let config = link_only_config(path_3dsx).unwrap_or_else(|e| {
+                eprintln!("{e}");
+                process::exit(1);
+            });
+            if !self.build_args.quiet {
                 eprintln!("Running 3dslink");
+            }
+            link(&config, self, self.build_args.verbose);
+            return;
+        }
+
+        if self.no_build {
+            if let Some(cfg) = config.as_ref().filter(|cfg| !cfg.path_3dsx().exists()) {
+                eprintln!(
+                    "No previously built 3dsx found at {}",
+                    cfg.path_3dsx().display()
+                );
+                process::exit(1);
+            }
+        } else {
+            // Run the normal "build" callback
+            self.build_args.callback(config);
+        }
+
+        // With a custom runner, cargo has already run the executable
+        if !self.use_custom_runner() && !self.build_args.skips_3dsx() {
+            if let Some(cfg) = config {
+                if !self.build_args.quiet {
+                    eprintln!("Running 3dslink");
+                }
                 link(cfg, self, self.build_args.verbose);
             }
         }
@@ -422,11 +1566,33 @@ impl Run {
     /// - `.cargo/config.toml`
     /// - Environment variables
     /// - Command-line `--config` overrides
+    ///
+    /// Always returns `false` if `--no-custom-runner`, or an option implying it
+    /// (like `--no-build` or `--smdh-only`), was passed.
     pub fn use_custom_runner(&self) -> bool {
+        self.use_custom_runner_with(Self::custom_runner_configured)
+    }
+
+    /// Like [`Run::use_custom_runner`], using `runner_configured` to determine
+    /// whether `target.armv6k-nintendo-3ds.runner` is configured.
+    fn use_custom_runner_with(&self, runner_configured: impl FnOnce(&Run) -> bool) -> bool {
+        if self.no_custom_runner
+            || self.no_build
+            || self.link_only
+            || self.stdio_over_network
+            || self.build_args.skips_3dsx()
+        {
+            return false;
+        }
+
+        runner_configured(self)
+    }
+
+    fn custom_runner_configured(&self) -> bool {
         static HAS_RUNNER: OnceLock<bool> = OnceLock::new();
 
         let &custom_runner_configured = HAS_RUNNER.get_or_init(|| {
-            let mut cmd = cargo(&self.config);
+            let mut cmd = cargo(&self.build_args.config);
             cmd.args([
                 // https://github.com/rust-lang/cargo/issues/9301
                 "-Z",
@@ -443,7 +1609,7 @@ impl Run {
             }
 
             // `cargo config get` exits zero if the config exists, or nonzero otherwise
-            cmd.status().map_or(false, |status| status.success())
+            cmd.status().is_ok_and(|status| status.success())
         });
 
         if self.build_args.verbose {
@@ -457,12 +1623,38 @@ impl Run {
     }
 }
 
+/// The environment variable with the simulator used by `cargo 3ds test --simulator`.
+const SIMULATOR_VAR: &str = "CARGO_3DS_SIMULATOR";
+
+/// How long `cargo 3ds test --simulator` waits for the simulator to exit,
+/// unless `--simulator-timeout` is given. Most simulators keep running after
+/// the tests are done.
+const DEFAULT_SIMULATOR_TIMEOUT: Duration = Duration::from_secs(300);
+
 impl Test {
     /// Callback for `cargo 3ds test`.
     ///
     /// This callback handles launching the application via `3dslink`.
     fn callback(&self, config: &Option<CTRConfig>) {
-        if self.no_run {
+        if self.simulator.is_some() {
+            self.run_args.build_args.callback(config);
+            if let Some(config) = config
+                .as_ref()
+                .filter(|_| !self.run_args.build_args.skips_3dsx())
+            {
+                // Checked by `CargoCmd::check_options`
+                let program = self
+                    .simulator_program(env::var(SIMULATOR_VAR).ok())
+                    .expect("no simulator configured");
+                let timeout = self
+                    .simulator_timeout
+                    .map_or(DEFAULT_SIMULATOR_TIMEOUT, Duration::from_secs);
+                if !self.run_args.build_args.quiet {
+                    eprintln!("Running tests with {program}");
+                }
+                simulate(config, &program, timeout, self.run_args.build_args.verbose);
+            }
+        } else if self.no_run || (self.doc && !self.should_run()) {
             // If the tests don't have to run, use the "build" callback
             self.run_args.build_args.callback(config);
         } else {
@@ -472,7 +1664,15 @@ impl Test {
     }
 
     fn should_run(&self) -> bool {
-        self.run_args.use_custom_runner() && !self.no_run
+        self.run_args.use_custom_runner() && !self.no_run && self.simulator.is_none()
+    }
+
+    /// The simulator to run the tests with, from `--simulator=PROGRAM` or
+    /// else `env_program`, the value of `CARGO_3DS_SIMULATOR`.
+    fn simulator_program(&self, env_program: Option<String>) -> Option<String> {
+        self.simulator
+            .clone()?
+            .or_else(|| env_program.filter(|program| !program.is_empty()))
     }
 
     /// The args to pass to the underlying `cargo test` command.
@@ -512,6 +1712,142 @@ impl Test {
     }
 }
 
+impl Bench {
+    /// Callback for `cargo 3ds bench`.
+    ///
+    /// This callback handles launching the benchmarks via `3dslink`.
+    fn callback(&self, config: &Option<CTRConfig>) {
+        if self.should_run() || self.no_run {
+            // cargo has already run the benchmarks, or they don't have to run
+            self.run_args.build_args.callback(config);
+        } else {
+            self.link_args().callback(config);
+        }
+    }
+
+    fn should_run(&self) -> bool {
+        self.run_args.use_custom_runner() && !self.no_run
+    }
+
+    /// The options to send the benchmarks with `3dslink`. The harness only
+    /// runs benchmarks (instead of testing them once) when given `--bench`,
+    /// which `cargo bench` passes before the executable arguments.
+    fn link_args(&self) -> Run {
+        let mut run_args = self.run_args.clone();
+        let passthrough = &mut run_args.build_args.passthrough;
+        let (cargo_args, exe_args) = passthrough.split_args();
+        passthrough.args = cargo_args
+            .into_iter()
+            .chain([String::from("--"), String::from("--bench")])
+            .chain(exe_args)
+            .collect();
+        run_args
+    }
+
+    /// The args to pass to the underlying `cargo bench` command.
+    fn cargo_args(&self) -> Vec<String> {
+        let mut cargo_args = self.run_args.build_args.passthrough.cargo_args();
+
+        // Like tests, benchmarks can only be run on the host by a custom runner
+        if !self.should_run() {
+            cargo_args.push("--no-run".into());
+        }
+
+        cargo_args
+    }
+}
+
+impl Size {
+    /// Callback for `cargo 3ds size`.
+    ///
+    /// This callback reports the size of the built executable.
+    fn callback(&self, config: &Option<CTRConfig>) {
+        if let Some(config) = config {
+            print_size(config, self.build_args.verbose);
+        }
+    }
+}
+
+/// Environment variable with the path of the SD card when `--sd` isn't given.
+const SD_VAR: &str = "CARGO_3DS_SD";
+
+impl Install {
+    /// Callback for `cargo 3ds install`.
+    ///
+    /// This callback builds the `.3dsx` and copies it to the SD card.
+    fn callback(&self, config: &Option<CTRConfig>) {
+        let Some(cfg) = config else {
+            return;
+        };
+
+        let install_dir = self.install_dir(cfg).unwrap_or_else(|e| {
+            eprintln!("{e}");
+            process::exit(1);
+        });
+
+        // Run the normal "build" callback
+        self.build_args.callback(config);
+        if self.build_args.skips_3dsx() {
+            return;
+        }
+
+        // The SMDH is named after the `.3dsx`, even if it was given with `--smdh`
+        let mut files = vec![(cfg.path_3dsx(), cfg.path_3dsx())];
+        if !self.build_args.no_smdh {
+            let smdh = self
+                .build_args
+                .smdh
+                .clone()
+                .unwrap_or_else(|| cfg.path_smdh());
+            files.push((smdh, cfg.path_smdh()));
+        }
+
+        fs::create_dir_all(&install_dir).unwrap_or_else(|e| {
+            eprintln!("Could not create {}: {e}", install_dir.display());
+            process::exit(1);
+        });
+        for (file, name) in files {
+            let destination = install_dir.join(name.file_name().unwrap());
+            if let Err(e) = fs::copy(&file, &destination) {
+                eprintln!(
+                    "Could not copy {} to {}: {e}",
+                    file.display(),
+                    destination.display()
+                );
+                process::exit(1);
+            }
+        }
+
+        if !self.build_args.quiet {
+            eprintln!("Installed to {}", install_dir.display());
+        }
+    }
+
+    /// The directory of the SD card to copy the built files to, named after
+    /// the `.3dsx` in the `3ds` directory.
+    fn install_dir(&self, config: &CTRConfig) -> Result<PathBuf, String> {
+        let sd = self
+            .sd
+            .clone()
+            .or_else(|| {
+                env::var_os(SD_VAR)
+                    .filter(|sd| !sd.is_empty())
+                    .map(PathBuf::from)
+            })
+            .ok_or_else(|| format!("error: no SD card path, pass `--sd` or set {SD_VAR}"))?;
+
+        if !sd.is_dir() {
+            return Err(format!(
+                "error: SD card path {} is not a directory, is the SD card mounted?",
+                sd.display()
+            ));
+        }
+
+        let name = config.path_3dsx().file_stem().unwrap().to_owned();
+        Ok(sd.join("3ds").join(name))
+    }
+}
+
 const TOML_CHANGES: &str = r#"ctru-rs = { git = "https://github.com/rust3ds/ctru-rs" }
 
 [package.metadata.cargo-3ds]
@@ -583,6 +1919,47 @@ mod tests {
 
     use super::*;
 
+    fn build_with_args(args: &[&str]) -> CargoCmd {
+        CargoCmd::Build(Build {
+            passthrough: RemainingArgs {
+                args: args.iter().map(ToString::to_string).collect(),
+            },
+            verbose: false,
+            quiet: false,
+            config: Vec::new(),
+            color: ColorChoice::Auto,
+            jobs: None,
+            target_dir: None,
+            manifest_path: None,
+            profile: None,
+            profiles: Vec::new(),
+            keep_going: false,
+            features: Vec::new(),
+            all_features: false,
+            no_default_features: false,
+            print_3dsx_path: false,
+            post_build: None,
+            app_name: None,
+            app_desc: None,
+            app_author: None,
+            output_name: None,
+            no_smdh: false,
+            smdh_only: false,
+            no_3dsx: false,
+            smdh: None,
+            print_config: false,
+            list_romfs: false,
+            json_messages: false,
+            stamp_version: false,
+            desc_suffix: None,
+            strict: false,
+            require_icon: false,
+            romfs_compress: false,
+            target_cpu: None,
+            rustc_codegen: Vec::new(),
+        })
+    }
+
     #[test]
     fn verify_app() {
         Cargo::command().debug_assert();
@@ -607,15 +1984,41 @@ mod tests {
                 Some("json-render-diagnostics"),
             ),
             (&["--foo", "bar"], None),
+            (
+                &["--foo", "--message-format=json-diagnostic-short", "bar"],
+                Some("json-diagnostic-short"),
+            ),
+            (
+                &[
+                    "--foo",
+                    "--message-format",
+                    "json-diagnostic-rendered-ansi",
+                    "bar",
+                ],
+                Some("json-diagnostic-rendered-ansi"),
+            ),
+            (
+                &[
+                    "--message-format=json",
+                    "--foo",
+                    "--message-format",
+                    "json",
+                    "bar",
+                ],
+                Some("json"),
+            ),
+            (
+                &[
+                    "--foo",
+                    "--message-format=json,json-diagnostic-rendered-ansi",
+                    "bar",
+                ],
+                Some("json,json-diagnostic-rendered-ansi"),
+            ),
         ];
 
         for (args, expected) in CASES {
-            let mut cmd = CargoCmd::Build(Build {
-                passthrough: RemainingArgs {
-                    args: args.iter().map(ToString::to_string).collect(),
-                },
-                verbose: false,
-            });
+            let mut cmd = build_with_args(args);
 
             assert_eq!(
                 cmd.extract_message_format().unwrap(),
@@ -632,13 +2035,17 @@ mod tests {
 
     #[test]
     fn extract_format_err() {
-        for args in [&["--message-format=foo"][..], &["--message-format", "foo"]] {
-            let mut cmd = CargoCmd::Build(Build {
-                passthrough: RemainingArgs {
-                    args: args.iter().map(ToString::to_string).collect(),
-                },
-                verbose: false,
-            });
+        for args in [
+            &["--message-format=foo"][..],
+            &["--message-format", "foo"],
+            &["--message-format"],
+            &["--message-format=json,short"],
+            &[
+                "--message-format=json",
+                "--message-format=json-render-diagnostics",
+            ],
+        ] {
+            let mut cmd = build_with_args(args);
 
             assert!(cmd.extract_message_format().is_err());
         }
@@ -693,4 +2100,1075 @@ mod tests {
             assert_eq!(build_args.passthrough.exe_args(), param.expected_exe);
         }
     }
+
+    #[test]
+    fn codegen_flags() {
+        for (input, flags) in [
+            (&[][..], &[][..]),
+            (&["--target-cpu", "mpcore"], &["-Ctarget-cpu=mpcore"]),
+            (
+                &[
+                    "--rustc-codegen",
+                    "opt-level=s",
+                    "--target-cpu=mpcore",
+                    "--rustc-codegen=lto",
+                ],
+                &["-Ctarget-cpu=mpcore", "-Copt-level=s", "-Clto"],
+            ),
+        ] {
+            let input: Vec<&str> = ["cargo", "3ds", "build"]
+                .iter()
+                .chain(input)
+                .copied()
+                .collect();
+            let Cargo::Input(Input {
+                cmd: CargoCmd::Build(build),
+                ..
+            }) = Cargo::try_parse_from(&input).unwrap_or_else(|e| panic!("{e}"))
+            else {
+                panic!("parsed as something other than `build` subcommand")
+            };
+
+            assert_eq!(build.codegen_flags(), flags);
+        }
+    }
+
+    #[test]
+    fn features() {
+        for (input, features, cargo_args, exe_args) in [
+            (
+                &["--features", "foo", "--", "--", "arg"][..],
+                &["foo"][..],
+                &[][..],
+                &["arg"][..],
+            ),
+            (
+                &[
+                    "-F",
+                    "foo,bar",
+                    "--no-default-features",
+                    "--release",
+                    "--",
+                    "arg",
+                ],
+                &["foo,bar"],
+                &["--release"],
+                &["arg"],
+            ),
+            (
+                &["--features=foo", "--all-features", "--features", "bar"],
+                &["foo", "bar"],
+                &[],
+                &[],
+            ),
+        ] {
+            let input: Vec<&str> = ["cargo", "3ds", "run"]
+                .iter()
+                .chain(input)
+                .copied()
+                .collect();
+            let Cargo::Input(Input {
+                cmd: CargoCmd::Run(Run { build_args, .. }),
+                ..
+            }) = Cargo::try_parse_from(&input).unwrap_or_else(|e| panic!("{e}"))
+            else {
+                panic!("parsed as something other than `run` subcommand")
+            };
+
+            assert_eq!(build_args.features, features, "{input:?}");
+            assert_eq!(build_args.passthrough.cargo_args(), cargo_args, "{input:?}");
+            assert_eq!(build_args.passthrough.exe_args(), exe_args, "{input:?}");
+        }
+    }
+
+    #[test]
+    fn check_target() {
+        for (input, ok) in [
+            (&["build", "--release"][..], true),
+            (&["build", "--target", "armv6k-nintendo-3ds"], true),
+            (&["run", "--target=armv6k-nintendo-3ds"], true),
+            (&["build", "--target", "x86_64-unknown-linux-gnu"], false),
+            (&["test", "--target=x86_64-unknown-linux-gnu"], false),
+            (&["clippy", "--target", "x86_64-unknown-linux-gnu"], false),
+            (
+                &["run", "--", "--", "--target", "x86_64-unknown-linux-gnu"],
+                true,
+            ),
+        ] {
+            let input: Vec<&str> = ["cargo", "3ds"].iter().chain(input).copied().collect();
+            let Cargo::Input(Input { cmd, .. }) =
+                Cargo::try_parse_from(&input).unwrap_or_else(|e| panic!("{e}"));
+
+            assert_eq!(cmd.check_target().is_ok(), ok, "{input:?}");
+        }
+    }
+
+    #[test]
+    fn aliases() {
+        for (alias, command) in [("b", "build"), ("r", "run"), ("t", "test")] {
+            let Cargo::Input(Input { cmd, .. }) =
+                Cargo::try_parse_from(["cargo", "3ds", alias, "--release"])
+                    .unwrap_or_else(|e| panic!("{e}"));
+
+            let parsed = match cmd {
+                CargoCmd::Build(_) => "build",
+                CargoCmd::Run(_) => "run",
+                CargoCmd::Test(_) => "test",
+                _ => "other",
+            };
+            assert_eq!(parsed, command);
+            assert!(cmd.should_build_3dsx(), "{alias}");
+            assert_eq!(cmd.cargo_args()[0], "--release");
+        }
+    }
+
+    #[test]
+    fn expand_cargo_alias() {
+        let args: Vec<OsString> = ["cargo", "3ds", "-v", "br", "--example", "foo"]
+            .map(OsString::from)
+            .into();
+        let Cargo::Input(Input { cmd, .. }) =
+            Cargo::try_parse_from(&args).unwrap_or_else(|e| panic!("{e}"));
+
+        let lookup = |alias: &str| {
+            assert_eq!(alias, "br");
+            Some(vec![String::from("build"), String::from("--release")])
+        };
+        let expanded = expand_alias(&args, &cmd, lookup).unwrap();
+        assert_eq!(
+            expanded,
+            [
+                "cargo",
+                "3ds",
+                "-v",
+                "build",
+                "--release",
+                "--example",
+                "foo"
+            ]
+        );
+
+        let Cargo::Input(Input { cmd, .. }) =
+            Cargo::try_parse_from(&expanded).unwrap_or_else(|e| panic!("{e}"));
+        assert!(cmd.should_build_3dsx());
+
+        assert_eq!(expand_alias(&args, &cmd, |_| unreachable!()), None);
+
+        let Cargo::Input(Input { cmd, .. }) =
+            Cargo::try_parse_from(["cargo", "3ds", "clippy"]).unwrap_or_else(|e| panic!("{e}"));
+        assert_eq!(expand_alias(&args, &cmd, |_| None), None);
+
+        // Cargo commands are never looked up as aliases
+        let args = ["cargo", "3ds", "check"].map(OsString::from);
+        let Cargo::Input(Input { cmd, .. }) =
+            Cargo::try_parse_from(&args).unwrap_or_else(|e| panic!("{e}"));
+        assert_eq!(expand_alias(&args, &cmd, |_| unreachable!()), None);
+    }
+
+    #[test]
+    fn install_dir() {
+        let sd = env::temp_dir();
+        let sd_arg = format!("--sd={}", sd.display());
+        let input = ["cargo", "3ds", "install", &sd_arg, "--release"];
+        let Cargo::Input(Input {
+            cmd: cmd @ CargoCmd::Install(_),
+            ..
+        }) = Cargo::try_parse_from(input).unwrap_or_else(|e| panic!("{e}"))
+        else {
+            panic!("parsed as something other than `install` subcommand")
+        };
+
+        assert_eq!(cmd.subcommand_name(), "build");
+        assert_eq!(cmd.cargo_args(), ["--release"]);
+        assert!(cmd.should_build_3dsx());
+
+        let CargoCmd::Install(install) = cmd else {
+            unreachable!()
+        };
+        let config = CTRConfig::builder()
+            .name("Hello")
+            .target_path("/target/hello.elf")
+            .build();
+        assert_eq!(install.install_dir(&config), Ok(sd.join("3ds/hello")));
+
+        let input = ["cargo", "3ds", "install", "--sd=/does/not/exist"];
+        let Cargo::Input(Input {
+            cmd: CargoCmd::Install(install),
+            ..
+        }) = Cargo::try_parse_from(input).unwrap_or_else(|e| panic!("{e}"))
+        else {
+            panic!("parsed as something other than `install` subcommand")
+        };
+        assert!(install.install_dir(&config).is_err());
+    }
+
+    #[test]
+    fn no_3dsx() {
+        for input in [
+            &["build", "--no-3dsx"][..],
+            &["build", "--no-3dsx", "--no-smdh"],
+        ] {
+            let input: Vec<&str> = ["cargo", "3ds"].iter().chain(input).copied().collect();
+            let Cargo::Input(Input { cmd, .. }) =
+                Cargo::try_parse_from(&input).unwrap_or_else(|e| panic!("{e}"));
+
+            // The executable is still compiled, but not converted
+            assert_eq!(cmd.check_options(), Ok(()), "{input:?}");
+            assert!(cmd.should_run_cargo(), "{input:?}");
+            assert!(cmd.build_args().unwrap().skips_3dsx(), "{input:?}");
+        }
+    }
+
+    #[test]
+    fn build_only_options() {
+        for command in ["run", "test", "bench", "install", "size"] {
+            for flag in ["--no-3dsx", "--smdh-only", "--print-config"] {
+                let Cargo::Input(Input { cmd, .. }) =
+                    Cargo::try_parse_from(["cargo", "3ds", command, flag])
+                        .unwrap_or_else(|e| panic!("{e}"));
+
+                assert!(cmd.check_options().is_err(), "{command} {flag}");
+            }
+        }
+    }
+
+    #[test]
+    fn smdh_only() {
+        let Cargo::Input(Input { cmd, .. }) =
+            Cargo::try_parse_from(["cargo", "3ds", "build", "--smdh-only"])
+                .unwrap_or_else(|e| panic!("{e}"));
+
+        assert!(cmd.build_args().unwrap().smdh_only);
+        assert!(cmd.build_args().unwrap().skips_3dsx());
+
+        assert!(
+            Cargo::try_parse_from(["cargo", "3ds", "build", "--smdh-only", "--no-smdh"]).is_err()
+        );
+    }
+
+    #[test]
+    fn exe_help() {
+        for (input, exe_args) in [
+            (&["--", "--", "--help"][..], &["--help"][..]),
+            (&["--", "--", "-h"], &["-h"]),
+            (
+                &["--release", "--", "--help", "--", "-h"],
+                &["--help", "--", "-h"],
+            ),
+        ] {
+            let input: Vec<&str> = ["cargo", "3ds", "run"]
+                .iter()
+                .chain(input)
+                .copied()
+                .collect();
+            let Cargo::Input(Input {
+                cmd: CargoCmd::Run(run),
+                ..
+            }) = Cargo::try_parse_from(&input).unwrap_or_else(|e| panic!("{input:?}: {e}"))
+            else {
+                panic!("parsed as something other than `run` subcommand")
+            };
+
+            assert_eq!(run.build_args.passthrough.exe_args(), exe_args, "{input:?}");
+            assert!(!run
+                .build_args
+                .passthrough
+                .cargo_args()
+                .contains(&"--help".to_string()));
+        }
+
+        let input = [
+            "cargo",
+            "3ds",
+            "run",
+            "--address=192.168.0.2",
+            "--",
+            "--",
+            "--help",
+        ];
+        let Cargo::Input(Input {
+            cmd: CargoCmd::Run(run),
+            ..
+        }) = Cargo::try_parse_from(input).unwrap_or_else(|e| panic!("{e}"))
+        else {
+            panic!("parsed as something other than `run` subcommand")
+        };
+        assert_eq!(
+            run.get_3dslink_args_with_env(
+                &CTRConfig::default(),
+                &Cargo3dsMetadata::default(),
+                None
+            ),
+            ["--address", "192.168.0.2", "--args", "--", "--", "--help"]
+        );
+    }
+
+    #[test]
+    fn exe_args_for_3dslink() {
+        // 3dslink takes a value for `--args`, which is the first `--`. Its
+        // own options end at the next `--`, so any arguments after it are
+        // passed to the executable even if they start with `-`.
+        for (exe_args, expected) in [
+            (&["pos"][..], &["--args", "--", "pos"][..]),
+            (&["-flag"], &["--args", "--", "--", "-flag"]),
+            (&["pos", "-flag"], &["--args", "--", "pos", "--", "-flag"]),
+            (&["-flag", "pos"], &["--args", "--", "--", "-flag", "pos"]),
+            (
+                &["-flag", "-flag2"],
+                &["--args", "--", "--", "-flag", "-flag2"],
+            ),
+            (&["pos", "pos2"], &["--args", "--", "pos", "pos2"]),
+            (
+                &["pos", "--", "x"],
+                &["--args", "--", "pos", "--", "--", "x"],
+            ),
+            (&[], &[]),
+        ] {
+            let input: Vec<&str> = ["cargo", "3ds", "run", "--address=192.168.0.2", "--", "--"]
+                .iter()
+                .chain(exe_args)
+                .copied()
+                .collect();
+            let Cargo::Input(Input {
+                cmd: CargoCmd::Run(run),
+                ..
+            }) = Cargo::try_parse_from(&input).unwrap_or_else(|e| panic!("{input:?}: {e}"))
+            else {
+                panic!("parsed as something other than `run` subcommand")
+            };
+
+            let args = run.get_3dslink_args_with_env(
+                &CTRConfig::default(),
+                &Cargo3dsMetadata::default(),
+                None,
+            );
+            assert_eq!(args[..2], ["--address", "192.168.0.2"]);
+            assert_eq!(args[2..], *expected, "{exe_args:?}");
+        }
+    }
+
+    #[test]
+    fn extra_3dslink_args() {
+        let input = [
+            "cargo",
+            "3ds",
+            "run",
+            "--address=192.168.0.2",
+            "--3dslink-arg",
+            "--new-option",
+            "--3dslink-arg=value",
+            "--server",
+            "--",
+            "--",
+            "pos",
+        ];
+        let Cargo::Input(Input {
+            cmd: CargoCmd::Run(run),
+            ..
+        }) = Cargo::try_parse_from(input).unwrap_or_else(|e| panic!("{e}"))
+        else {
+            panic!("parsed as something other than `run` subcommand")
+        };
+
+        assert_eq!(
+            run.get_3dslink_args_with_env(
+                &CTRConfig::default(),
+                &Cargo3dsMetadata::default(),
+                None
+            ),
+            [
+                "--address",
+                "192.168.0.2",
+                "--server",
+                "--new-option",
+                "value",
+                "--args",
+                "--",
+                "pos"
+            ]
+        );
+    }
+
+    #[test]
+    fn link_only() {
+        let dir = env::temp_dir().join(format!("cargo-3ds-link-only-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path_3dsx = dir.join("cargo-3ds-link-only.3dsx");
+        fs::write(&path_3dsx, "").unwrap();
+        let path_arg = format!("--3dsx={}", path_3dsx.display());
+
+        let input = ["cargo", "3ds", "run", "--link-only", &path_arg];
+        let Cargo::Input(Input { cmd, .. }) =
+            Cargo::try_parse_from(input).unwrap_or_else(|e| panic!("{e}"));
+        assert!(!cmd.should_run_cargo());
+        assert!(!cmd.should_get_metadata());
+        assert!(!cmd.run_args().unwrap().use_custom_runner());
+
+        let config = link_only_config(&path_3dsx).unwrap();
+        assert_eq!(config.name(), "cargo-3ds-link-only");
+        assert_eq!(config.path_3dsx(), path_3dsx);
+
+        assert!(link_only_config(&path_3dsx.with_extension("elf")).is_err());
+        assert!(link_only_config(Path::new("/does/not/exist.3dsx")).is_err());
+
+        // `--link-only` and `--3dsx` require each other
+        assert!(Cargo::try_parse_from(["cargo", "3ds", "run", "--link-only"]).is_err());
+        assert!(Cargo::try_parse_from(["cargo", "3ds", "run", &path_arg]).is_err());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn print_config() {
+        let Cargo::Input(Input { cmd, .. }) =
+            Cargo::try_parse_from(["cargo", "3ds", "build", "--print-config", "--release"])
+                .unwrap_or_else(|e| panic!("{e}"));
+
+        let build = cmd.build_args().unwrap();
+        assert!(build.print_config);
+        assert!(build.skips_3dsx());
+        assert_eq!(build.passthrough.cargo_args(), ["--release"]);
+    }
+
+    #[test]
+    fn list_romfs() {
+        for command in ["build", "run", "install"] {
+            let Cargo::Input(Input { cmd, .. }) =
+                Cargo::try_parse_from(["cargo", "3ds", command, "--list-romfs"])
+                    .unwrap_or_else(|e| panic!("{e}"));
+
+            // Listing the RomFS doesn't need anything to be built
+            assert!(cmd.build_args().unwrap().skips_3dsx(), "{command}");
+            assert!(!cmd.should_run_cargo(), "{command}");
+            assert!(!cmd.should_get_metadata(), "{command}");
+        }
+    }
+
+    #[test]
+    fn requested_features() {
+        for (input, features) in [
+            (&["build"][..], &["default"][..]),
+            (
+                &[
+                    "build",
+                    "--features",
+                    "hd-assets,sound",
+                    "--no-default-features",
+                ],
+                &["hd-assets", "sound"],
+            ),
+            (
+                &["build", "--features=hd-assets", "--", "--features", "a b"],
+                &["hd-assets", "a", "b", "default"],
+            ),
+            (&["build", "--", "--no-default-features"], &[]),
+        ] {
+            let input: Vec<&str> = ["cargo", "3ds"].iter().chain(input).copied().collect();
+            let Cargo::Input(Input { cmd, .. }) =
+                Cargo::try_parse_from(&input).unwrap_or_else(|e| panic!("{e}"));
+
+            assert_eq!(
+                cmd.build_args().unwrap().requested_features(),
+                features,
+                "{input:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn selected_profile() {
+        for (input, profile) in [
+            (&["build"][..], None),
+            (&["build", "--release"], Some("release")),
+            (&["build", "-r"], Some("release")),
+            (
+                &["build", "--profile", "release-small"],
+                Some("release-small"),
+            ),
+            (
+                &["run", "--lib", "--profile=release-small"],
+                Some("release-small"),
+            ),
+            (&["run", "--", "--", "--release"], None),
+        ] {
+            let input: Vec<&str> = ["cargo", "3ds"].iter().chain(input).copied().collect();
+            let Cargo::Input(Input { cmd, .. }) =
+                Cargo::try_parse_from(&input).unwrap_or_else(|e| panic!("{e}"));
+
+            assert_eq!(
+                cmd.build_args().unwrap().selected_profile().as_deref(),
+                profile,
+                "{input:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn no_build() {
+        for (input, allowed) in [
+            (&["run", "--no-build"][..], true),
+            (&["test", "--no-build"], false),
+            (&["bench", "--no-build"], false),
+        ] {
+            let input: Vec<&str> = ["cargo", "3ds"].iter().chain(input).copied().collect();
+            let Cargo::Input(Input { cmd, .. }) =
+                Cargo::try_parse_from(&input).unwrap_or_else(|e| panic!("{e}"));
+
+            assert_eq!(cmd.check_options().is_ok(), allowed, "{input:?}");
+        }
+    }
+
+    #[test]
+    fn multiple_profiles() {
+        let Cargo::Input(Input { mut cmd, .. }) =
+            Cargo::try_parse_from(["cargo", "3ds", "build", "--profiles", "debug,release"])
+                .unwrap_or_else(|e| panic!("{e}"));
+        assert_eq!(cmd.check_profiles(), Ok(()));
+        assert_eq!(cmd.take_profiles(), ["debug", "release"]);
+
+        cmd.set_profile("debug");
+        assert_eq!(
+            cmd.build_args().unwrap().selected_profile().as_deref(),
+            Some("dev")
+        );
+        cmd.set_profile("release");
+        assert_eq!(
+            cmd.build_args().unwrap().selected_profile().as_deref(),
+            Some("release")
+        );
+
+        for input in [
+            &["build", "--profiles", "dev,release", "--release"][..],
+            &["run", "--profiles", "dev,release"],
+        ] {
+            let input: Vec<&str> = ["cargo", "3ds"].iter().chain(input).copied().collect();
+            let Cargo::Input(Input { cmd, .. }) =
+                Cargo::try_parse_from(&input).unwrap_or_else(|e| panic!("{e}"));
+            assert!(cmd.check_profiles().is_err(), "{input:?}");
+        }
+
+        assert!(Cargo::try_parse_from([
+            "cargo",
+            "3ds",
+            "build",
+            "--profiles",
+            "dev,release",
+            "--profile",
+            "dev"
+        ])
+        .is_err());
+
+        assert!(Cargo::try_parse_from(["cargo", "3ds", "build", "--keep-going"]).is_err());
+    }
+
+    #[test]
+    fn quiet() {
+        let Cargo::Input(input) = Cargo::try_parse_from(["cargo", "3ds", "build", "-q"])
+            .unwrap_or_else(|e| panic!("{e}"));
+        assert!(input.quiet);
+        assert!(input.cmd.build_args().unwrap().quiet);
+
+        assert_eq!(input.check_verbosity(), Ok(()));
+
+        for input in [
+            &["cargo", "3ds", "-q", "build", "-v"][..],
+            &["cargo", "3ds", "run", "--quiet", "--verbose"],
+        ] {
+            let Cargo::Input(input) =
+                Cargo::try_parse_from(input).unwrap_or_else(|e| panic!("{e}"));
+            assert!(input.check_verbosity().is_err(), "{input:?}");
+        }
+    }
+
+    #[test]
+    fn selected_target() {
+        for (input, target) in [
+            (&["build"][..], None),
+            (&["build", "--bin", "foo"], Some("foo")),
+            (&["run", "--release", "--example=bar"], Some("bar")),
+            (
+                &["test", "--bin", "foo", "--", "--", "--bin", "baz"],
+                Some("foo"),
+            ),
+        ] {
+            let input: Vec<&str> = ["cargo", "3ds"].iter().chain(input).copied().collect();
+            let Cargo::Input(Input { cmd, .. }) =
+                Cargo::try_parse_from(&input).unwrap_or_else(|e| panic!("{e}"));
+
+            assert_eq!(
+                cmd.build_args().unwrap().selected_target().as_deref(),
+                target,
+                "{input:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn stdio_over_network() {
+        let input = ["cargo", "3ds", "run", "--stdio-over-network"];
+        let Cargo::Input(Input {
+            cmd: CargoCmd::Run(run),
+            ..
+        }) = Cargo::try_parse_from(input).unwrap_or_else(|e| panic!("{e}"))
+        else {
+            panic!("parsed as something other than `run` subcommand")
+        };
+
+        assert_eq!(
+            run.get_3dslink_args_with_env(
+                &CTRConfig::default(),
+                &Cargo3dsMetadata::default(),
+                None
+            ),
+            ["--server"]
+        );
+        assert!(!run.use_custom_runner());
+    }
+
+    #[test]
+    fn parse_run_address() {
+        for (address, valid) in [
+            ("192.168.0.2", true),
+            ("3ds.local", true),
+            ("my-3ds.local", true),
+            ("fe80::1", true),
+            ("", false),
+            ("my 3ds", false),
+            ("-s", false),
+        ] {
+            let address_arg = format!("--address={address}");
+            let input = ["cargo", "3ds", "run", &address_arg];
+
+            match Cargo::try_parse_from(input) {
+                Ok(Cargo::Input(Input {
+                    cmd: CargoCmd::Run(run),
+                    ..
+                })) => {
+                    assert!(valid, "{address:?} should have been rejected");
+                    assert_eq!(
+                        run.get_3dslink_args_with_env(
+                            &CTRConfig::default(),
+                            &Cargo3dsMetadata::default(),
+                            None
+                        ),
+                        ["--address", address]
+                    );
+                }
+                Ok(_) => panic!("parsed as something other than `run` subcommand"),
+                Err(e) => assert!(!valid, "{address:?} should have been accepted: {e}"),
+            }
+        }
+    }
+
+    #[test]
+    fn argv0_template() {
+        let config = CTRConfig::builder().name("hello").build();
+        for (argv0, expected) in [
+            ("sdmc:/3ds/{name}/{name}.3dsx", "sdmc:/3ds/hello/hello.3dsx"),
+            ("sdmc:/3ds/app.3dsx", "sdmc:/3ds/app.3dsx"),
+        ] {
+            let argv0_arg = format!("--argv0={argv0}");
+            let input = ["cargo", "3ds", "run", "--address=192.168.0.2", &argv0_arg];
+            let Cargo::Input(Input {
+                cmd: CargoCmd::Run(run),
+                ..
+            }) = Cargo::try_parse_from(input).unwrap_or_else(|e| panic!("{e}"))
+            else {
+                panic!("parsed as something other than `run` subcommand")
+            };
+
+            assert_eq!(
+                run.get_3dslink_args_with_env(&config, &Cargo3dsMetadata::default(), None),
+                ["--address", "192.168.0.2", "--arg0", expected]
+            );
+        }
+    }
+
+    #[test]
+    fn address_precedence() {
+        let parse_run = |args: &[&str]| {
+            let input = ["cargo", "3ds", "run"].iter().chain(args);
+            match Cargo::try_parse_from(input).unwrap_or_else(|e| panic!("{e}")) {
+                Cargo::Input(Input {
+                    cmd: CargoCmd::Run(run),
+                    ..
+                }) => run,
+                _ => panic!("parsed as something other than `run` subcommand"),
+            }
+        };
+        let settings = Cargo3dsMetadata {
+            address: Some("192.168.0.3".to_string()),
+            ..Default::default()
+        };
+        let no_settings = Cargo3dsMetadata::default();
+
+        for (args, settings, env_address, expected) in [
+            (
+                &["--address=192.168.0.2"][..],
+                &settings,
+                Some("192.168.0.4"),
+                Some("192.168.0.2"),
+            ),
+            (&[], &settings, Some("192.168.0.4"), Some("192.168.0.3")),
+            (&[], &no_settings, Some("192.168.0.4"), Some("192.168.0.4")),
+            (&[], &no_settings, Some(""), None),
+            (&[], &no_settings, None, None),
+        ] {
+            let expected: Vec<_> = expected
+                .into_iter()
+                .flat_map(|a| ["--address", a])
+                .collect();
+            assert_eq!(
+                parse_run(args).get_3dslink_args_with_env(
+                    &CTRConfig::default(),
+                    settings,
+                    env_address.map(String::from)
+                ),
+                expected,
+                "{args:?} {env_address:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn run_mode() {
+        use RunMode::*;
+
+        const CASES: &[(&[&str], RunMode, RunMode)] = &[
+            // (args, mode without custom runner, mode with custom runner)
+            (&["build"], NoRun, NoRun),
+            (&["run"], Link, CustomRunner),
+            (&["test"], Link, CustomRunner),
+            (&["test", "--lib"], Link, CustomRunner),
+            (&["test", "--no-run"], NoRun, NoRun),
+            (&["test", "--doc"], NoRun, CustomRunner),
+            (&["test", "--doc", "--no-run"], NoRun, NoRun),
+            (&["test", "--doc", "--no-custom-runner"], NoRun, NoRun),
+            (&["run", "--no-custom-runner"], Link, Link),
+            (&["test", "--no-custom-runner"], Link, Link),
+            (&["test", "--simulator"], Simulator, Simulator),
+            (&["test", "--simulator=citra"], Simulator, Simulator),
+            (&["bench"], Link, CustomRunner),
+            (&["bench", "--no-run"], NoRun, NoRun),
+            (&["check"], NoRun, NoRun),
+        ];
+
+        for &(args, without_runner, with_runner) in CASES {
+            let input = ["cargo", "3ds"].iter().chain(args);
+            let Cargo::Input(Input { cmd, .. }) =
+                Cargo::try_parse_from(input).unwrap_or_else(|e| panic!("{e}"));
+
+            assert_eq!(cmd.run_mode_with(|_| false), without_runner, "{args:?}");
+            assert_eq!(cmd.run_mode_with(|_| true), with_runner, "{args:?}");
+        }
+    }
+
+    #[test]
+    fn release_shorthand() {
+        for (input, retries) in [
+            (&["-r"][..], None),
+            (&["--retries", "3", "-r"], Some(3)),
+            (&["--retries=3", "-r", "--", "-r"], Some(3)),
+        ] {
+            let input: Vec<&str> = ["cargo", "3ds", "run"]
+                .iter()
+                .chain(input)
+                .copied()
+                .collect();
+            let Cargo::Input(Input {
+                cmd: CargoCmd::Run(run),
+                ..
+            }) = Cargo::try_parse_from(&input).unwrap_or_else(|e| panic!("{e}"))
+            else {
+                panic!("parsed as something other than `run` subcommand")
+            };
+
+            // `-r` is cargo's `--release`, not a short form of `--retries`
+            assert_eq!(run.retries, retries, "{input:?}");
+            assert_eq!(run.build_args.passthrough.cargo_args(), ["-r"], "{input:?}");
+            assert_eq!(
+                run.build_args.selected_profile().as_deref(),
+                Some("release"),
+                "{input:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn bench_args() {
+        for (input, exe_args) in [
+            (&["--example", "foo"][..], &["--bench"][..]),
+            (&["--", "--", "filter"], &["--bench", "filter"]),
+            (
+                &["--release", "--", "--exact", "a"],
+                &["--bench", "--exact", "a"],
+            ),
+        ] {
+            let input: Vec<&str> = ["cargo", "3ds", "bench", "--no-custom-runner"]
+                .iter()
+                .chain(input)
+                .copied()
+                .collect();
+            let Cargo::Input(Input { cmd, .. }) =
+                Cargo::try_parse_from(&input).unwrap_or_else(|e| panic!("{e}"));
+            let CargoCmd::Bench(bench) = &cmd else {
+                panic!("parsed as something other than `bench` subcommand")
+            };
+
+            assert_eq!(cmd.subcommand_name(), "bench");
+            assert!(cmd.should_build_3dsx());
+            assert_eq!(cmd.cargo_args().last().unwrap(), "--no-run", "{input:?}");
+
+            let link_args = bench.link_args();
+            assert_eq!(link_args.build_args.passthrough.exe_args(), exe_args);
+            assert_eq!(
+                link_args.build_args.passthrough.cargo_args(),
+                bench.run_args.build_args.passthrough.cargo_args()
+            );
+        }
+    }
+
+    #[test]
+    fn simulator() {
+        for (input, simulator, cargo_args) in [
+            (&["--simulator"][..], Some(None), &["--no-run"][..]),
+            (
+                &["--simulator=/opt/citra", "--lib"],
+                Some(Some("/opt/citra")),
+                &["--lib", "--no-run"],
+            ),
+            // The program can't be given without `=`, so this is a test filter
+            (&["--simulator", "foo"], Some(None), &["foo", "--no-run"]),
+            (&["--no-custom-runner"], None, &["--no-run"]),
+        ] {
+            let input: Vec<&str> = ["cargo", "3ds", "test"]
+                .iter()
+                .chain(input)
+                .copied()
+                .collect();
+            let Cargo::Input(Input { cmd, .. }) =
+                Cargo::try_parse_from(&input).unwrap_or_else(|e| panic!("{e}"));
+            let CargoCmd::Test(test) = &cmd else {
+                panic!("parsed as something other than `test` subcommand")
+            };
+
+            assert_eq!(
+                test.simulator.as_ref().map(Option::as_deref),
+                simulator,
+                "{input:?}"
+            );
+            assert_eq!(cmd.cargo_args(), cargo_args, "{input:?}");
+        }
+
+        for conflict in ["--no-run", "--doc"] {
+            assert!(
+                Cargo::try_parse_from(["cargo", "3ds", "test", "--simulator", conflict]).is_err()
+            );
+        }
+        assert!(Cargo::try_parse_from(["cargo", "3ds", "test", "--simulator-timeout=10"]).is_err());
+    }
+
+    #[test]
+    fn simulator_program() {
+        let parse_test = |args: &[&str]| {
+            let input = ["cargo", "3ds", "test"].iter().chain(args);
+            match Cargo::try_parse_from(input).unwrap_or_else(|e| panic!("{e}")) {
+                Cargo::Input(Input {
+                    cmd: CargoCmd::Test(test),
+                    ..
+                }) => test,
+                _ => panic!("parsed as something other than `test` subcommand"),
+            }
+        };
+
+        for (args, env_program, expected) in [
+            (
+                &["--simulator=/opt/citra"][..],
+                Some("lime3ds"),
+                Some("/opt/citra"),
+            ),
+            (&["--simulator"], Some("lime3ds"), Some("lime3ds")),
+            (&["--simulator"], Some(""), None),
+            (&["--simulator"], None, None),
+            (&[], Some("lime3ds"), None),
+        ] {
+            assert_eq!(
+                parse_test(args)
+                    .simulator_program(env_program.map(String::from))
+                    .as_deref(),
+                expected,
+                "{args:?} {env_program:?}"
+            );
+        }
+
+        let Cargo::Input(Input { cmd, .. }) = Cargo::try_parse_from([
+            "cargo",
+            "3ds",
+            "test",
+            "--simulator=/opt/citra",
+            "--",
+            "--",
+            "my_test",
+        ])
+        .unwrap_or_else(|e| panic!("{e}"));
+        assert!(cmd.check_options().is_err());
+    }
+
+    #[test]
+    fn doc_tests_never_link() {
+        let Cargo::Input(Input { cmd, .. }) =
+            Cargo::try_parse_from(["cargo", "3ds", "test", "--doc"])
+                .unwrap_or_else(|e| panic!("{e}"));
+        let CargoCmd::Test(test) = &cmd else {
+            panic!("parsed as something other than `test` subcommand")
+        };
+        assert!(test.doc);
+        assert!(!test.no_run);
+
+        assert!(!cmd.should_build_3dsx());
+        assert!(!cmd.should_get_metadata());
+        assert_ne!(cmd.run_mode_with(|_| false), RunMode::Link);
+        assert_ne!(cmd.run_mode_with(|_| true), RunMode::Link);
+    }
+
+    #[test]
+    fn unstable_flags() {
+        const CASES: &[(&[&str], bool)] = &[
+            (&["build"], false),
+            (
+                &["build", "-Zbuild-std-features=panic_immediate_abort"],
+                false,
+            ),
+            (&["build", "-Z", "build-std"], true),
+            (&["-Zbuild-std=core,alloc", "build"], true),
+            (&["check", "-Zbuild-std=std"], true),
+            (&["check", "-Z", "build-std"], true),
+            (&["run", "-Z", "build-std", "--", "arg"], true),
+            (&["build", "--", "-Z", "build-std=core"], true),
+            (&["run", "--", "--", "-Z", "build-std"], false),
+            (&["clippy", "--", "-Z", "build-std"], false),
+        ];
+
+        for &(args, has_build_std) in CASES {
+            let input = ["cargo", "3ds"].iter().chain(args);
+            let Cargo::Input(input) =
+                Cargo::try_parse_from(input).unwrap_or_else(|e| panic!("{e}"));
+
+            assert_eq!(input.has_build_std(), has_build_std, "{args:?}");
+        }
+    }
+
+    #[test]
+    fn parse_jobs() {
+        for (args, expected) in [
+            (&["build"][..], None),
+            (&["build", "-j", "4"], Some(4)),
+            (&["run", "--jobs=-1", "--release"], Some(-1)),
+            (&["test", "-j", "2", "--lib"], Some(2)),
+        ] {
+            let input = ["cargo", "3ds"].iter().chain(args);
+            let Cargo::Input(Input { cmd, .. }) =
+                Cargo::try_parse_from(input).unwrap_or_else(|e| panic!("{e}"));
+
+            assert_eq!(cmd.build_args().unwrap().jobs, expected, "{args:?}");
+        }
+    }
+
+    #[test]
+    fn override_config() {
+        let input = [
+            "cargo",
+            "3ds",
+            "build",
+            "--app-name",
+            "My App build 42",
+            "--app-author=Me",
+            "--stamp-version",
+            "--desc-suffix",
+            "nightly",
+        ];
+        let Cargo::Input(Input { cmd, .. }) =
+            Cargo::try_parse_from(input).unwrap_or_else(|e| panic!("{e}"));
+
+        let mut config = CTRConfig {
+            description: String::from("From Cargo.toml"),
+            version: Some(semver::Version::new(1, 2, 3)),
+            ..Default::default()
+        };
+        cmd.build_args().unwrap().override_config(&mut config);
+
+        assert_eq!(config.name, "My App build 42");
+        assert_eq!(config.author, "Me");
+        assert_eq!(config.description, "From Cargo.toml (v1.2.3) nightly");
+    }
+
+    #[test]
+    fn output_name() {
+        let input = [
+            "cargo",
+            "3ds",
+            "build",
+            "--app-name",
+            "My Cool App",
+            "--output-name",
+            "my-app",
+        ];
+        let Cargo::Input(Input { cmd, .. }) =
+            Cargo::try_parse_from(input).unwrap_or_else(|e| panic!("{e}"));
+
+        let mut config = CTRConfig {
+            name: String::from("hello"),
+            target_path: PathBuf::from("target/armv6k-nintendo-3ds/debug/hello.elf"),
+            ..Default::default()
+        };
+        let build = cmd.build_args().unwrap();
+        build.override_config(&mut config);
+
+        // The name shown in the HOME menu and the file names are independent
+        assert_eq!(config.name, "My Cool App");
+        assert_eq!(
+            config.path_3dsx(),
+            Path::new("target/armv6k-nintendo-3ds/debug/my-app.3dsx")
+        );
+        assert_eq!(
+            config.path_smdh(),
+            Path::new("target/armv6k-nintendo-3ds/debug/my-app.smdh")
+        );
+
+        for name in ["", "..", "dir/my-app"] {
+            let input = ["cargo", "3ds", "build", "--output-name", name];
+            assert!(Cargo::try_parse_from(input).is_err(), "{name:?}");
+        }
+    }
+
+    #[test]
+    fn metadata_args() {
+        let input = [
+            "cargo",
+            "3ds",
+            "--config=net.offline=true",
+            "run",
+            "--release",
+            "--frozen",
+            "--target-dir",
+            "custom target",
+            "--manifest-path",
+            "app/Cargo.toml",
+            "--",
+            "--",
+            "--offline",
+        ];
+        let Cargo::Input(Input { cmd, .. }) =
+            Cargo::try_parse_from(input).unwrap_or_else(|e| panic!("{e}"));
+
+        assert_eq!(
+            cmd.build_args().unwrap().metadata_args(),
+            [
+                "--config=net.offline=true",
+                "--frozen",
+                "--manifest-path=app/Cargo.toml",
+                r#"--config=build.target-dir="custom target""#
+            ]
+        );
+    }
 }
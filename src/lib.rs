@@ -1,19 +1,32 @@
 pub mod command;
 
 use core::fmt;
-use std::io::{BufRead, BufReader};
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, UdpSocket};
 use std::path::{Path, PathBuf};
-use std::process::{Command, ExitStatus, Stdio};
+use std::process::{Child, Command, ExitStatus, Stdio};
+#[cfg(unix)]
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{mpsc, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 use std::{env, io, process};
 
-use cargo_metadata::{Message, MetadataCommand};
+use cargo_metadata::diagnostic::Diagnostic;
+use cargo_metadata::{Message, Metadata, MetadataCommand, Package, Target};
 use command::{Input, Test};
 use cytryna::smdh::Smdh;
 use rustc_version::Channel;
 use semver::Version;
+use serde::{Deserialize, Serialize};
 use tee::TeeReader;
 
-use crate::command::{CargoCmd, Run};
+use crate::command::{Build, CargoCmd, ColorChoice, Run, RunMode};
+
+/// The target triple of the 3DS, which `cargo-3ds` always builds for.
+pub const TARGET: &str = "armv6k-nintendo-3ds";
 
 /// Build a command using [`make_cargo_build_command`] and execute it,
 /// parsing and returning the messages from the spawned process.
@@ -27,13 +40,32 @@ pub fn run_cargo(input: &Input, message_format: Option<String>) -> (ExitStatus,
         print_command(&command);
     }
 
+    if let Some(build) = input.cmd.build_args() {
+        warn_if_rust_flags_changed(&command, &build.metadata_args());
+    }
+
     let mut process = command.spawn().unwrap();
+    let _forward_signals = ForwardSignals::new(&process);
     let command_stdout = process.stdout.take().unwrap();
 
-    let mut tee_reader;
-    let mut stdout_reader;
+    let tee = tees_output(&message_format, &input.cmd).then(io::stdout);
+    let messages = read_messages(command_stdout, tee).unwrap();
+
+    (process.wait().unwrap(), messages)
+}
+
+/// Parse the messages in the output of cargo, copying the output to `tee`
+/// unchanged if given.
+fn read_messages(output: impl Read, tee: Option<impl Write>) -> io::Result<Vec<Message>> {
+    match tee {
+        Some(tee) => Message::parse_stream(BufReader::new(TeeReader::new(output, tee))).collect(),
+        None => Message::parse_stream(BufReader::new(output)).collect(),
+    }
+}
 
-    let buf_reader: &mut dyn BufRead = match (message_format, &input.cmd) {
+/// Whether the output of cargo is copied to stdout while its messages are parsed.
+fn tees_output(message_format: &Option<String>, cmd: &CargoCmd) -> bool {
+    match (message_format, cmd) {
         // The user presumably cares about the message format if set, so we should
         // copy stuff to stdout like they expect. We can still extract the executable
         // information out of it that we need for 3dsxtool etc.
@@ -41,46 +73,252 @@ pub fn run_cargo(input: &Input, message_format: Option<String>) -> (ExitStatus,
         // Rustdoc unfortunately prints to stdout for compile errors, so
         // we also use a tee when building doc tests too.
         // Possibly related: https://github.com/rust-lang/rust/issues/75135
-        (None, CargoCmd::Test(Test { doc: true, .. })) => {
-            tee_reader = BufReader::new(TeeReader::new(command_stdout, io::stdout()));
-            &mut tee_reader
-        }
-        _ => {
-            stdout_reader = BufReader::new(command_stdout);
-            &mut stdout_reader
+        (None, CargoCmd::Test(Test { doc: true, .. })) => true,
+        _ => false,
+    }
+}
+
+/// Whether cargo reported the build as successful in `messages`, as returned
+/// by [`run_cargo`]. This is `false` if the build didn't finish.
+pub fn build_succeeded(messages: &[Message]) -> bool {
+    messages
+        .iter()
+        .rev()
+        .any(|message| matches!(message, Message::BuildFinished(finished) if finished.success))
+}
+
+/// The compiler diagnostics (errors, warnings, etc.) in `messages`, as returned
+/// by [`run_cargo`], in the order they were emitted.
+pub fn diagnostics(messages: &[Message]) -> Vec<&Diagnostic> {
+    messages
+        .iter()
+        .filter_map(|message| match message {
+            Message::CompilerMessage(compiler_message) => Some(&compiler_message.message),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Name of the file in the target directory where the rustflags of the last
+/// build are stored.
+const RUST_FLAGS_CACHE: &str = ".cargo-3ds-rustflags";
+
+/// Warn if the rustflags set for `command` differ from the ones of the
+/// previous build, since cargo then rebuilds everything (including std).
+fn warn_if_rust_flags_changed(command: &Command, metadata_args: &[String]) {
+    let Some(rust_flags) = command.get_envs().find_map(|(var, value)| {
+        if var != "RUSTFLAGS" && var != "CARGO_ENCODED_RUSTFLAGS" {
+            return None;
         }
+        Some(format!(
+            "{}={}",
+            var.to_string_lossy(),
+            value?.to_string_lossy()
+        ))
+    }) else {
+        return;
     };
 
-    let messages = Message::parse_stream(buf_reader)
-        .collect::<io::Result<_>>()
-        .unwrap();
+    // This is only a hint, so the build goes on if the target dir isn't known
+    let Ok(metadata) = try_cargo_metadata(metadata_args) else {
+        return;
+    };
+    let cache = metadata
+        .target_directory
+        .as_std_path()
+        .join(TARGET)
+        .join(RUST_FLAGS_CACHE);
 
-    (process.wait().unwrap(), messages)
+    if let Some(previous) = update_rust_flags_cache(&cache, &rust_flags) {
+        eprintln!(
+            "warning: rustflags changed since the last build, so everything will be rebuilt.\n  \
+            previous: {}\n  current: {}",
+            previous.escape_debug(),
+            rust_flags.escape_debug(),
+        );
+    }
+}
+
+/// Store `rust_flags` in the `cache` file, returning the previously stored
+/// flags if they were different.
+fn update_rust_flags_cache(cache: &Path, rust_flags: &str) -> Option<String> {
+    let previous = std::fs::read_to_string(cache).ok();
+    if previous.as_deref() == Some(rust_flags) {
+        return None;
+    }
+
+    if let Some(dir) = cache.parent() {
+        // Failing to store the flags only means the next build can't be compared
+        let _ = std::fs::create_dir_all(dir).and_then(|()| std::fs::write(cache, rust_flags));
+    }
+
+    previous
+}
+
+/// While alive, forwards `SIGTERM` received by `cargo-3ds` to a child process
+/// instead of exiting immediately, so that terminating `cargo-3ds` doesn't leave
+/// the child running. `SIGINT` is ignored instead: Ctrl-C is already sent to the
+/// child by the terminal, and forwarding it would interrupt the child twice.
+/// The child then exits and is waited on as usual.
+///
+/// Only one child is supported at a time.
+struct ForwardSignals {
+    #[cfg(unix)]
+    previous: Vec<(libc::c_int, libc::sighandler_t)>,
+}
+
+#[cfg(unix)]
+static FORWARD_SIGNALS_PID: AtomicI32 = AtomicI32::new(0);
+
+#[cfg(unix)]
+extern "C" fn forward_signal(signal: libc::c_int) {
+    let pid = FORWARD_SIGNALS_PID.load(Ordering::SeqCst);
+    if pid > 0 {
+        // SAFETY: `kill` is async-signal-safe
+        unsafe { libc::kill(pid, signal) };
+    }
+}
+
+impl ForwardSignals {
+    #[cfg(unix)]
+    fn new(child: &Child) -> Self {
+        let pid = child.id() as libc::pid_t;
+        FORWARD_SIGNALS_PID.store(pid, Ordering::SeqCst);
+
+        let handler = forward_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        let previous = [(libc::SIGINT, libc::SIG_IGN), (libc::SIGTERM, handler)]
+            .into_iter()
+            // SAFETY: the handler only calls async-signal-safe functions
+            .map(|(signal, handler)| (signal, unsafe { libc::signal(signal, handler) }))
+            .collect();
+
+        Self { previous }
+    }
+
+    #[cfg(not(unix))]
+    fn new(_child: &Child) -> Self {
+        // Ctrl-C is sent to every process attached to the console, so the
+        // child is interrupted along with `cargo-3ds` already.
+        Self {}
+    }
+}
+
+#[cfg(unix)]
+impl Drop for ForwardSignals {
+    fn drop(&mut self) {
+        for &(signal, handler) in &self.previous {
+            // SAFETY: this restores the handler replaced in `ForwardSignals::new`
+            unsafe { libc::signal(signal, handler) };
+        }
+        FORWARD_SIGNALS_PID.store(0, Ordering::SeqCst);
+    }
 }
 
 /// Create a cargo command based on the context.
 ///
 /// For "build" commands (which compile code, such as `cargo 3ds build` or `cargo 3ds clippy`),
-/// if there is no pre-built std detected in the sysroot, `build-std` will be used instead.
+/// if there is no pre-built std detected in the sysroot, `build-std` will be used instead
+/// (unless disabled with `--no-build-std`).
 pub fn make_cargo_command(input: &Input, message_format: &Option<String>) -> Command {
+    let (settings, manifest_dir) = if input.cmd.should_compile() {
+        build_settings(&input.cmd)
+    } else {
+        Default::default()
+    };
+    make_cargo_command_with_env(
+        input,
+        message_format,
+        &CargoEnv::from_env(),
+        &settings,
+        &manifest_dir,
+    )
+}
+
+/// The environment variables read by [`make_cargo_command`].
+#[derive(Debug, Default, Clone)]
+pub struct CargoEnv {
+    /// `DEVKITPRO`, the path of the devkitPro installation.
+    pub devkitpro: Option<String>,
+    /// `RUSTFLAGS`.
+    pub rust_flags: Option<String>,
+    /// `CARGO_ENCODED_RUSTFLAGS`, which takes precedence over `RUSTFLAGS`.
+    pub encoded_rust_flags: Option<String>,
+    /// `RUSTDOCFLAGS`.
+    pub rustdoc_flags: Option<String>,
+    /// `CARGO_TARGET_ARMV6K_NINTENDO_3DS_RUSTFLAGS`, which overrides the
+    /// configured `target.armv6k-nintendo-3ds.rustflags`.
+    pub target_rust_flags: Option<String>,
+    /// `CARGO_BUILD_RUSTFLAGS`, which overrides the configured `build.rustflags`.
+    pub build_rust_flags: Option<String>,
+}
+
+impl CargoEnv {
+    /// Read the variables from the environment of `cargo-3ds`.
+    pub fn from_env() -> Self {
+        Self {
+            devkitpro: env::var("DEVKITPRO").ok(),
+            rust_flags: env::var("RUSTFLAGS").ok(),
+            encoded_rust_flags: env::var("CARGO_ENCODED_RUSTFLAGS").ok(),
+            rustdoc_flags: env::var("RUSTDOCFLAGS").ok(),
+            target_rust_flags: env::var("CARGO_TARGET_ARMV6K_NINTENDO_3DS_RUSTFLAGS").ok(),
+            build_rust_flags: env::var("CARGO_BUILD_RUSTFLAGS").ok(),
+        }
+    }
+}
+
+/// Like [`make_cargo_command`], with the environment variables from `cargo_env`
+/// instead of the process environment, and the `settings` of the package being
+/// built, whose manifest is in `manifest_dir`, instead of reading them with
+/// `cargo metadata`.
+pub fn make_cargo_command_with_env(
+    input: &Input,
+    message_format: &Option<String>,
+    cargo_env: &CargoEnv,
+    settings: &Cargo3dsMetadata,
+    manifest_dir: &Path,
+) -> Command {
     let cargo_cmd = &input.cmd;
 
     let mut command = cargo(&input.config);
-    command.arg(cargo_cmd.subcommand_name());
+    command
+        .arg(format!("--color={}", input.color.resolve()))
+        .arg(cargo_cmd.subcommand_name())
+        .args(input.unstable_flags.iter().flat_map(|flag| ["-Z", flag]));
+
+    if input.quiet {
+        command.arg("--quiet");
+    }
 
     // Any command that needs to compile code will run under this environment.
     // Even `clippy` and `check` need this kind of context, so we'll just assume any other `Passthrough` command uses it too.
     if cargo_cmd.should_compile() {
-        let rust_flags = env::var("RUSTFLAGS").unwrap_or_default()
-            + &format!(
-                " -L{}/libctru/lib -lctru",
-                env::var("DEVKITPRO").expect("DEVKITPRO is not defined as an environment variable")
-            );
+        let devkitpro = cargo_env
+            .devkitpro
+            .as_deref()
+            .expect("DEVKITPRO is not defined as an environment variable");
+        // The libraries (`-lctru` by default) must come after the search path,
+        // and both after the user's flags. rustc passes `-C link-arg`s to the
+        // linker after its own arguments, so they can't shadow libctru or be
+        // reordered before it.
+        let mut flags = link_flags(devkitpro, settings, manifest_dir);
+        if let Some(build) = cargo_cmd.build_args() {
+            flags.extend(build.codegen_flags());
+        }
+
+        let mut encoded = cargo_env.encoded_rust_flags.clone();
+        let plain = cargo_env.rust_flags.clone();
+        if encoded.is_none() && plain.is_none() {
+            // Cargo ignores the configured rustflags when they are set in the
+            // environment, so they are passed along with ours
+            encoded = config_rust_flags(cargo_env, |key| cargo_config_value(&input.config, key))
+                .map(|flags| flags.join("\x1f"));
+        }
+        let (rust_flags_var, rust_flags) = append_rust_flags(encoded, plain, &flags);
 
         command
-            .env("RUSTFLAGS", rust_flags)
+            .env(rust_flags_var, rust_flags)
             .arg("--target")
-            .arg("armv6k-nintendo-3ds")
+            .arg(TARGET)
             .arg("--message-format")
             .arg(
                 message_format
@@ -89,8 +327,26 @@ pub fn make_cargo_command(input: &Input, message_format: &Option<String>) -> Com
             );
 
         let sysroot = find_sysroot();
-        if !sysroot.join("lib/rustlib/armv6k-nintendo-3ds").exists() {
-            eprintln!("No pre-build std found, using build-std");
+        if !input.has_build_std() && !sysroot.join("lib/rustlib").join(TARGET).exists() {
+            if input.no_build_std {
+                eprintln!(
+                    "No pre-built std found in {}, and `--no-build-std` was passed",
+                    sysroot.display()
+                );
+                process::exit(1);
+            }
+
+            if input.verbose {
+                eprintln!("No pre-build std found, using build-std");
+            }
+            // Cargo's own error for this is hard to make sense of
+            if !has_rust_src(&sysroot) {
+                eprintln!(
+                    "warning: build-std needs the standard library's source, which wasn't found in {}.\n  \
+                    Run `rustup component add rust-src` to install it.",
+                    sysroot.display()
+                );
+            }
             // Always building the test crate is not ideal, but we don't know if the
             // crate being built uses #![feature(test)], so we build it just in case.
             command.arg("-Z").arg("build-std=std,test");
@@ -99,14 +355,49 @@ pub fn make_cargo_command(input: &Input, message_format: &Option<String>) -> Com
 
     if let CargoCmd::Test(test) = cargo_cmd {
         // RUSTDOCFLAGS is simply ignored if --doc wasn't passed, so we always set it.
-        let rustdoc_flags = std::env::var("RUSTDOCFLAGS").unwrap_or_default() + test.rustdocflags();
-        command.env("RUSTDOCFLAGS", rustdoc_flags);
+        // Like rustflags, the configured ones are ignored when it is set.
+        let config_flags = match cargo_env.rustdoc_flags {
+            Some(_) => None,
+            None => config_rustdoc_flags(&input.config),
+        };
+        let (rustdoc_flags_var, rustdoc_flags) = append_rustdoc_flags(
+            cargo_env.rustdoc_flags.clone(),
+            config_flags,
+            test.rustdocflags(),
+        );
+        command.env(rustdoc_flags_var, rustdoc_flags);
+    }
+
+    if let Some(build) = cargo_cmd.build_args() {
+        if let Some(jobs) = build.jobs {
+            command.arg(format!("--jobs={jobs}"));
+        }
+        if let Some(target_dir) = &build.target_dir {
+            command.arg("--target-dir").arg(target_dir);
+        }
+        if let Some(manifest_path) = &build.manifest_path {
+            command.arg("--manifest-path").arg(manifest_path);
+        }
+        if let Some(profile) = &build.profile {
+            command.arg(format!("--profile={profile}"));
+        }
+        for features in &build.features {
+            command.arg(format!("--features={features}"));
+        }
+        if build.all_features {
+            command.arg("--all-features");
+        }
+        if build.no_default_features {
+            command.arg("--no-default-features");
+        }
     }
 
     command.args(cargo_cmd.cargo_args());
 
-    if let CargoCmd::Run(run) | CargoCmd::Test(Test { run_args: run, .. }) = &cargo_cmd {
-        if run.use_custom_runner() {
+    // Executable args are forwarded to the custom runner by cargo. Otherwise,
+    // they are given to `3dslink` in `link`, after the build has finished.
+    if let Some(run) = cargo_cmd.run_args() {
+        if cargo_cmd.run_mode() == RunMode::CustomRunner {
             command
                 .arg("--")
                 .args(run.build_args.passthrough.exe_args());
@@ -121,6 +412,156 @@ pub fn make_cargo_command(input: &Input, message_format: &Option<String>) -> Com
     command
 }
 
+/// The settings and directory of the package being built, selected with
+/// `--package` or `--manifest-path` like cargo does, or the default settings
+/// if it can't be found.
+fn build_settings(cargo_cmd: &CargoCmd) -> (Cargo3dsMetadata, PathBuf) {
+    let build = cargo_cmd.build_args();
+    let metadata_args = build.map(Build::metadata_args).unwrap_or_default();
+    let Ok(metadata) = try_cargo_metadata(&metadata_args) else {
+        return Default::default();
+    };
+
+    let Some(package) = selected_package(
+        metadata,
+        build.and_then(Build::selected_package_name).as_deref(),
+        build.and_then(Build::selected_manifest_path).as_deref(),
+    ) else {
+        return Default::default();
+    };
+    let manifest_path = package.manifest_path.as_std_path();
+    (
+        package_settings(manifest_path),
+        manifest_path.parent().unwrap().to_owned(),
+    )
+}
+
+/// The libraries linked when a package doesn't set `link_libs`.
+const DEFAULT_LINK_LIBS: &[&str] = &["ctru"];
+
+/// The rustc flags to link the libraries of `settings` from devkitPro, after
+/// the search paths. The package's `lib_paths`, relative to `manifest_dir`,
+/// are searched before libctru's directory so they can override its libraries.
+fn link_flags(devkitpro: &str, settings: &Cargo3dsMetadata, manifest_dir: &Path) -> Vec<String> {
+    let mut flags: Vec<String> = settings
+        .lib_paths
+        .iter()
+        .map(|path| {
+            let path = expand_env_vars(path, manifest_dir).unwrap_or_else(|e| {
+                eprintln!("Could not expand `lib_paths` entry {path:?}: {e}");
+                process::exit(1);
+            });
+            format!("-L{}", manifest_dir.join(path).display())
+        })
+        .collect();
+    flags.push(format!("-L{devkitpro}/libctru/lib"));
+    match &settings.link_libs {
+        Some(libs) => flags.extend(libs.iter().map(|lib| format!("-l{lib}"))),
+        None => flags.extend(DEFAULT_LINK_LIBS.iter().map(|lib| format!("-l{lib}"))),
+    }
+    flags
+}
+
+/// Append `flags` to the rustflags set in the environment, returning the
+/// environment variable to set and its new value.
+///
+/// Cargo ignores `RUSTFLAGS` if `CARGO_ENCODED_RUSTFLAGS` is set, so in that case
+/// the flags are appended to it instead, separated by `0x1f` as cargo expects.
+fn append_rust_flags(
+    encoded: Option<String>,
+    plain: Option<String>,
+    flags: &[String],
+) -> (&'static str, String) {
+    match (encoded, plain) {
+        (Some(encoded), _) => {
+            let flags = flags.join("\x1f");
+            let value = if encoded.is_empty() {
+                flags
+            } else {
+                encoded + "\x1f" + &flags
+            };
+            ("CARGO_ENCODED_RUSTFLAGS", value)
+        }
+        (None, plain) => (
+            "RUSTFLAGS",
+            plain.unwrap_or_default() + " " + &flags.join(" "),
+        ),
+    }
+}
+
+/// Append `flags` to the rustdoc flags from the `RUSTDOCFLAGS` environment
+/// variable, or to `config_flags` (the configured `build.rustdocflags`) if it
+/// isn't set, returning the environment variable to set and its new value.
+///
+/// The configured flags are passed with `CARGO_ENCODED_RUSTDOCFLAGS`, so flags
+/// containing spaces are kept intact.
+fn append_rustdoc_flags(
+    env_flags: Option<String>,
+    config_flags: Option<Vec<String>>,
+    flags: &str,
+) -> (&'static str, String) {
+    match (env_flags, config_flags) {
+        (None, Some(mut config_flags)) => {
+            config_flags.extend(flags.split_whitespace().map(String::from));
+            ("CARGO_ENCODED_RUSTDOCFLAGS", config_flags.join("\x1f"))
+        }
+        (env_flags, _) => ("RUSTDOCFLAGS", env_flags.unwrap_or_default() + flags),
+    }
+}
+
+/// Get the rustflags configured for the 3DS target, or the ones for all targets
+/// if there are none, like cargo does. Each is read from its environment
+/// variable in `cargo_env` if set, and otherwise with `config_value`, which
+/// looks up a key in cargo's configuration.
+fn config_rust_flags(
+    cargo_env: &CargoEnv,
+    config_value: impl Fn(&str) -> Option<toml::Value>,
+) -> Option<Vec<String>> {
+    let env_value = |flags: &Option<String>| flags.clone().map(toml::Value::String);
+    let flags = env_value(&cargo_env.target_rust_flags)
+        .or_else(|| config_value(&format!("target.{TARGET}.rustflags")))
+        .or_else(|| env_value(&cargo_env.build_rust_flags))
+        .or_else(|| config_value("build.rustflags"))?;
+    config_flags(flags)
+}
+
+/// Get the rustdoc flags configured with `build.rustdocflags` in cargo's configuration.
+fn config_rustdoc_flags(config: &[String]) -> Option<Vec<String>> {
+    config_flags(cargo_config_value(config, "build.rustdocflags")?)
+}
+
+/// Parse configured flags, which are either a space separated string or an
+/// array of strings.
+fn config_flags(flags: toml::Value) -> Option<Vec<String>> {
+    match flags {
+        toml::Value::String(flags) => Some(flags.split_whitespace().map(String::from).collect()),
+        toml::Value::Array(flags) => flags
+            .iter()
+            .map(|flag| flag.as_str().map(String::from))
+            .collect(),
+        _ => None,
+    }
+}
+
+/// Look up the dotted `key` in cargo's configuration, with the given
+/// `--config` flags. This uses the unstable `cargo config` command, so it
+/// returns `None` on stable toolchains.
+fn cargo_config_value(config: &[String], key: &str) -> Option<toml::Value> {
+    let output = cargo(config)
+        .args(["-Z", "unstable-options", "config", "get", key])
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+
+    let value: toml::Value = toml::from_str(std::str::from_utf8(&output.stdout).ok()?).ok()?;
+    key.split('.')
+        .try_fold(&value, |value, part| value.get(part))
+        .cloned()
+}
+
 /// Build a `cargo` command with the given `--config` flags.
 fn cargo(config: &[String]) -> Command {
     let cargo = env::var("CARGO").unwrap_or_else(|_| "cargo".to_string());
@@ -161,19 +602,28 @@ pub fn find_sysroot() -> PathBuf {
     PathBuf::from(sysroot.trim())
 }
 
+/// Whether the `rust-src` component, which `-Z build-std` builds the standard
+/// library from, is installed in `sysroot`.
+fn has_rust_src(sysroot: &Path) -> bool {
+    sysroot.join("lib/rustlib/src/rust/library").is_dir()
+}
+
 /// Checks the current rust version and channel.
 /// Exits if the minimum requirement is not met.
 pub fn check_rust_version() {
     let rustc_version = rustc_version::version_meta().unwrap();
 
-    if rustc_version.channel > Channel::Nightly {
-        eprintln!("cargo-3ds requires a nightly rustc version.");
+    if let Some(channel) = unsupported_channel(rustc_version.channel) {
+        eprintln!(
+            "cargo-3ds requires a nightly rustc version, but the current toolchain \
+            is on the {channel} channel."
+        );
         eprintln!(
             "Please run `rustup override set nightly` to use nightly in the \
             current directory, or use `cargo +nightly 3ds` to use it for a \
             single invocation."
         );
-        process::exit(1);
+        version_check_failed();
     }
 
     let old_version = MINIMUM_RUSTC_VERSION
@@ -195,6 +645,34 @@ pub fn check_rust_version() {
         eprintln!("cargo-3ds requires rustc nightly version >= {MINIMUM_COMMIT_DATE}");
         eprintln!("Please run `rustup update nightly` to upgrade your nightly version");
 
+        version_check_failed();
+    }
+}
+
+/// Returns the name of `channel` if it can't be used to build 3DS executables.
+///
+/// Locally built toolchains report the `dev` channel, and are allowed
+/// like nightly since they usually support unstable features.
+fn unsupported_channel(channel: Channel) -> Option<&'static str> {
+    match channel {
+        Channel::Dev | Channel::Nightly => None,
+        Channel::Beta => Some("beta"),
+        Channel::Stable => Some("stable"),
+    }
+}
+
+/// Environment variable which, when set to a value other than `0`, turns
+/// failures of [`check_rust_version`] into warnings.
+const SKIP_VERSION_CHECK_VAR: &str = "CARGO_3DS_SKIP_VERSION_CHECK";
+
+/// Exit after a failed version check, unless [`SKIP_VERSION_CHECK_VAR`] is set.
+fn version_check_failed() {
+    let skip =
+        env::var_os(SKIP_VERSION_CHECK_VAR).is_some_and(|value| !value.is_empty() && value != "0");
+
+    if skip {
+        eprintln!("warning: continuing anyway because {SKIP_VERSION_CHECK_VAR} is set");
+    } else {
         process::exit(1);
     }
 }
@@ -202,228 +680,3471 @@ pub fn check_rust_version() {
 /// Parses messages returned by "build" cargo commands (such as `cargo 3ds build` or `cargo 3ds run`).
 /// The returned [`CTRConfig`] is then used for further building in and execution
 /// in [`build_smdh`], [`build_3dsx`], and [`link`].
-pub fn get_metadata(messages: &[Message]) -> CTRConfig {
-    let metadata = MetadataCommand::new()
-        .no_deps()
-        .exec()
-        .expect("Failed to get cargo metadata");
+///
+/// `metadata_args` are passed to `cargo metadata`, see [`command::Build::metadata_args`].
+/// `previous` is given with `--no-build`, to find the executable built by a
+/// previous invocation since cargo doesn't report one.
+/// If `target_name` is given (with `--bin` or `--example`), only the executable
+/// of that target is used, see [`command::Build::selected_target`].
+pub fn get_metadata(
+    messages: &[Message],
+    metadata_args: &[String],
+    target_name: Option<&str>,
+    previous: Option<&PreviousBuild>,
+) -> CTRConfig {
+    let metadata = cargo_metadata(metadata_args);
 
-    let mut package = None;
-    let mut artifact = None;
+    let mut executable = None;
+    let mut features = None;
 
     // Extract the final built executable. We may want to fail in cases where
     // multiple executables, or none, were built?
     for message in messages.iter().rev() {
         if let Message::CompilerArtifact(art) = message {
-            if art.executable.is_some() {
-                package = Some(metadata[&art.package_id].clone());
-                artifact = Some(art.clone());
+            if target_name.is_some_and(|name| art.target.name != name) {
+                continue;
+            }
+            if let Some(path) = &art.executable {
+                let package = metadata[&art.package_id].clone();
+                executable = Some(((package, art.target.clone()), path.clone().into()));
+                features = Some(art.features.clone());
 
                 break;
             }
         }
     }
-    if package.is_none() || artifact.is_none() {
+
+    // Without a build (`--no-build`), use the executable built by a previous invocation
+    let executable = executable.or_else(|| {
+        let executable = find_previous_executable(metadata, previous?, target_name)?;
+        eprintln!("Using previously built {}", executable.1.display());
+        Some(executable)
+    });
+
+    let Some(((package, target), target_path)) = executable else {
         eprintln!("No executable found from build command output!");
         process::exit(1);
-    }
+    };
 
-    let (package, artifact) = (package.unwrap(), artifact.unwrap());
+    // The icon next to the package's manifest is preferred, so it is found
+    // when building with `--manifest-path` or from a workspace root
+    let manifest_path = package.manifest_path.as_std_path();
+    let icon_path = [
+        manifest_path.with_file_name("icon.png"),
+        manifest_path.with_file_name("icon.svg"),
+        PathBuf::from("./icon.png"),
+        PathBuf::from("./icon.svg"),
+    ]
+    .into_iter()
+    .find(|path| path.exists());
 
-    let mut icon_path = String::from("./icon.png");
+    let icon_path = icon_path.unwrap_or_else(|| {
+        let Some(devkitpro) = env::var_os("DEVKITPRO") else {
+            eprintln!("No icon.png found, and DEVKITPRO is not defined as an environment variable");
+            process::exit(1);
+        };
+        default_icon_path(Path::new(&devkitpro))
+    });
 
-    if !Path::new(&icon_path).exists() {
-        icon_path = format!(
-            "{}/libctru/default_icon.png",
-            env::var("DEVKITPRO").unwrap()
-        );
-    }
+    // A missing icon falls back to the default, but one that exists must be valid
+    let icon = load_icon(&icon_path, LARGE_ICON_SIZE).unwrap_or_else(|e| {
+        eprintln!("Could not load icon {}: {e}", icon_path.display());
+        process::exit(1);
+    });
 
-    let icon = image::open(Path::new(&icon_path)).expect("Invalid PNG image");
+    let settings = package_settings(package.manifest_path.as_std_path());
+    let example = example_name(&target).map(String::from);
+    let features = features.unwrap_or_else(|| {
+        let requested = previous.map_or(&[][..], |previous| &previous.features);
+        enabled_features(&package, requested)
+    });
+
+    let small_icon = settings.small_icon.as_ref().map(|path| {
+        let path = package.manifest_path.as_std_path().with_file_name(path);
+        let small_icon = load_icon(&path, SMALL_ICON_SIZE).unwrap_or_else(|e| {
+            eprintln!("Could not load small icon {}: {e}", path.display());
+            process::exit(1);
+        });
+        fit_small_icon(small_icon, &path)
+    });
 
     // for now assume a single "kind" since we only support one output artifact
-    let name = match artifact.target.kind[0].as_ref() {
-        "bin" | "lib" | "rlib" | "dylib" if artifact.target.test => {
-            format!("{} tests", artifact.target.name)
+    let name = match target.kind[0].as_ref() {
+        "bin" | "lib" | "rlib" | "dylib" if target.test => {
+            format!("{} tests", target.name)
         }
         "example" => {
-            format!("{} - {} example", artifact.target.name, package.name)
+            format!("{} - {} example", target.name, package.name)
         }
-        _ => artifact.target.name,
+        "bench" => {
+            format!("{} benchmarks", target.name)
+        }
+        _ => settings.name.unwrap_or(target.name),
     };
 
-    let author = match package.authors.as_slice() {
-        [name, ..] => name.clone(),
-        [] => String::from("Unspecified Author"), // as standard with the devkitPRO toolchain
-    };
+    let author = settings.author.unwrap_or_else(|| {
+        match package.authors.as_slice() {
+            [name, ..] => name.clone(),
+            [] => String::from("Unspecified Author"), // as standard with the devkitPRO toolchain
+        }
+    });
 
     CTRConfig {
         name,
         author,
-        description: package
+        description: settings
             .description
-            .clone()
+            .or_else(|| package.description.clone())
             .unwrap_or_else(|| String::from("Homebrew Application")),
         icon,
-        target_path: artifact.executable.unwrap().into(),
+        icon_path: Some(icon_path),
+        small_icon,
+        network: settings.network,
+        target_path,
         cargo_manifest_path: package.manifest_path.into(),
+        example,
+        features,
+        output_name: None,
+        version: Some(package.version),
     }
 }
 
-/// Builds the smdh using `cytryna` library.
-pub fn build_smdh(config: &CTRConfig) {
-    let smdh = Smdh::builder()
-        .with_short_desc(&config.name).unwrap()
-        .with_long_desc(&config.description).unwrap()
-        .with_publisher(&config.author).unwrap()
-        .with_icon((&config.icon).try_into().unwrap())
-        .build().expect("SMDH building failed");
+/// The features of `package` enabled by `requested`, for when cargo didn't
+/// report them. The default features are enabled by `default`, like with cargo,
+/// but other features enabling more features aren't followed.
+fn enabled_features(package: &Package, requested: &[String]) -> Vec<String> {
+    let mut features: Vec<String> = requested
+        .iter()
+        .filter(|feature| package.features.contains_key(*feature))
+        .cloned()
+        .collect();
+
+    if requested.iter().any(|feature| feature == "default") {
+        let defaults = package.features.get("default").into_iter().flatten();
+        features.extend(
+            defaults
+                .filter(|feature| package.features.contains_key(*feature))
+                .cloned(),
+        );
+    }
 
-    std::fs::write(config.path_smdh(), smdh.as_bytes()).expect("Failed to write SMDH data");
+    features.sort();
+    features.dedup();
+    features
 }
 
-/// Builds the 3dsx using `3dsxtool`.
-/// This will fail if `3dsxtool` is not within the running directory or in a directory found in $PATH
-pub fn build_3dsx(config: &CTRConfig, verbose: bool) {
-    let mut command = Command::new("3dsxtool");
-    command
-        .arg(&config.target_path)
-        .arg(config.path_3dsx())
-        .arg(format!("--smdh={}", config.path_smdh().to_string_lossy()));
+/// The name of the example built as `target`, including tests of examples.
+fn example_name(target: &Target) -> Option<&str> {
+    target
+        .kind
+        .iter()
+        .any(|kind| kind == "example")
+        .then_some(target.name.as_str())
+}
 
-    // If romfs directory exists, automatically include it
-    let (romfs_path, is_default_romfs) = get_romfs_path(config);
-    if romfs_path.is_dir() {
-        eprintln!("Adding RomFS from {}", romfs_path.display());
-        command.arg(format!("--romfs={}", romfs_path.to_string_lossy()));
-    } else if !is_default_romfs {
-        eprintln!(
-            "Could not find configured RomFS dir: {}",
-            romfs_path.display()
-        );
+/// Run `cargo metadata` with the given options. The result is cached, since it
+/// can be slow for large workspaces and doesn't change during a single invocation.
+fn cargo_metadata(metadata_args: &[String]) -> &'static Metadata {
+    try_cargo_metadata(metadata_args).unwrap_or_else(|e| {
+        eprintln!("Failed to get cargo metadata: {e}");
+        eprintln!("Another cargo process (e.g. rust-analyzer) may be running, try again later");
         process::exit(1);
-    }
+    })
+}
 
-    if verbose {
-        print_command(&command);
+/// Like [`cargo_metadata`], returning an error instead of exiting if
+/// `cargo metadata` fails.
+fn try_cargo_metadata(
+    metadata_args: &[String],
+) -> Result<&'static Metadata, cargo_metadata::Error> {
+    static METADATA: OnceLock<Mutex<HashMap<Vec<String>, &'static Metadata>>> = OnceLock::new();
+
+    let mut cache = METADATA.get_or_init(Default::default).lock().unwrap();
+    if let Some(metadata) = cache.get(metadata_args) {
+        return Ok(metadata);
     }
 
-    let mut process = command
-        .stdin(Stdio::inherit())
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
-        .spawn()
-        .expect("3dsxtool command failed, most likely due to '3dsxtool' not being in $PATH");
+    // Another cargo process (like rust-analyzer's) may be holding a lock
+    let metadata = retry_with_backoff(METADATA_ATTEMPTS, METADATA_BACKOFF, || {
+        MetadataCommand::new()
+            .no_deps()
+            .other_options(metadata_args.to_vec())
+            .exec()
+    })?;
 
-    let status = process.wait().unwrap();
+    let metadata = Box::leak(Box::new(metadata));
+    cache.insert(metadata_args.to_vec(), metadata);
+    Ok(metadata)
+}
 
-    if !status.success() {
-        process::exit(status.code().unwrap_or(1));
+/// Number of times to run `cargo metadata` before giving up.
+const METADATA_ATTEMPTS: u32 = 4;
+
+/// Delay before retrying `cargo metadata` for the first time, doubled after each try.
+const METADATA_BACKOFF: Duration = Duration::from_millis(250);
+
+/// Call `f` until it succeeds, at most `attempts` times, sleeping for `backoff`
+/// after the first failure and twice as long after each of the next ones.
+fn retry_with_backoff<T, E>(
+    attempts: u32,
+    mut backoff: Duration,
+    mut f: impl FnMut() -> Result<T, E>,
+) -> Result<T, E> {
+    let mut result = f();
+
+    for _ in 1..attempts {
+        if result.is_ok() {
+            break;
+        }
+        thread::sleep(backoff);
+        backoff *= 2;
+        result = f();
     }
+
+    result
 }
 
-/// Link the generated 3dsx to a 3ds to execute and test using `3dslink`.
-/// This will fail if `3dslink` is not within the running directory or in a directory found in $PATH
-pub fn link(config: &CTRConfig, run_args: &Run, verbose: bool) {
-    let mut command = Command::new("3dslink");
-    command
-        .arg(config.path_3dsx())
-        .args(run_args.get_3dslink_args())
-        .stdin(Stdio::inherit())
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit());
+/// Where to find an executable built by a previous invocation, when the build
+/// is skipped with `--no-build`. See [`command::Build::previous_build`].
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct PreviousBuild {
+    /// The profile the executable was built with, `dev` if unset.
+    pub profile: Option<String>,
+    /// The package selected with `--package`, otherwise the one of the manifest.
+    pub package: Option<String>,
+    /// The manifest given with `--manifest-path`, if any.
+    pub manifest_path: Option<PathBuf>,
+    /// The features requested for the build, see
+    /// [`command::Build::requested_features`].
+    pub features: Vec<String>,
+}
 
-    if verbose {
-        print_command(&command);
+/// The package selected with `--package` (`name`), or otherwise the one whose
+/// manifest is `manifest_path`, or is found from the current directory like
+/// cargo does. This is `None` for the manifest of a virtual workspace.
+fn selected_package<'a>(
+    metadata: &'a Metadata,
+    name: Option<&str>,
+    manifest_path: Option<&Path>,
+) -> Option<&'a Package> {
+    if let Some(name) = name {
+        return metadata
+            .packages
+            .iter()
+            .find(|package| package.name == name);
     }
 
-    let status = command.spawn().unwrap().wait().unwrap();
+    let manifest_path = match manifest_path {
+        Some(path) => path.to_path_buf(),
+        None => env::current_dir()
+            .ok()?
+            .ancestors()
+            .map(|dir| dir.join("Cargo.toml"))
+            .find(|path| path.is_file())?,
+    };
+    let manifest_path = manifest_path.canonicalize().ok()?;
 
-    if !status.success() {
-        process::exit(status.code().unwrap_or(1));
-    }
+    metadata.packages.iter().find(|package| {
+        package
+            .manifest_path
+            .as_std_path()
+            .canonicalize()
+            .is_ok_and(|path| path == manifest_path)
+    })
 }
 
-/// Read the `RomFS` path from the Cargo manifest. If it's unset, use the default.
-/// The returned boolean is true when the default is used.
-pub fn get_romfs_path(config: &CTRConfig) -> (PathBuf, bool) {
-    let manifest_path = &config.cargo_manifest_path;
-    let manifest_str = std::fs::read_to_string(manifest_path)
-        .unwrap_or_else(|e| panic!("Could not open {}: {e}", manifest_path.display()));
-    let manifest_data: toml::Value =
-        toml::de::from_str(&manifest_str).expect("Could not parse Cargo manifest as TOML");
+/// Find the executable of the selected package built with the selected profile
+/// by a previous invocation, using the default target directory layout.
+/// If `target_name` is given, only the binary or example with that name is
+/// considered, otherwise the package's `default-run` binary or its binaries.
+fn find_previous_executable(
+    metadata: &Metadata,
+    previous: &PreviousBuild,
+    target_name: Option<&str>,
+) -> Option<((Package, Target), PathBuf)> {
+    let package = selected_package(
+        metadata,
+        previous.package.as_deref(),
+        previous.manifest_path.as_deref(),
+    )?;
 
-    // Find the romfs setting and compute the path
-    let mut is_default = false;
-    let romfs_dir_setting = manifest_data
-        .as_table()
-        .and_then(|table| table.get("package"))
-        .and_then(toml::Value::as_table)
-        .and_then(|table| table.get("metadata"))
-        .and_then(toml::Value::as_table)
-        .and_then(|table| table.get("cargo-3ds"))
-        .and_then(toml::Value::as_table)
-        .and_then(|table| table.get("romfs_dir"))
-        .and_then(toml::Value::as_str)
-        .unwrap_or_else(|| {
-            is_default = true;
-            "romfs"
-        });
-    let mut romfs_path = manifest_path.clone();
-    romfs_path.pop(); // Pop Cargo.toml
-    romfs_path.push(romfs_dir_setting);
+    let profile = previous.profile.as_deref().unwrap_or("dev");
+    let profile_dir = metadata
+        .target_directory
+        .as_std_path()
+        .join(TARGET)
+        .join(profile_dir(profile));
+    let target_name = target_name.or(package.default_run.as_deref());
 
-    (romfs_path, is_default)
-}
+    let candidates = package
+        .targets
+        .iter()
+        .filter(|target| match target_name {
+            Some(name) => target.name == name,
+            None => target.kind.iter().any(|kind| kind == "bin"),
+        })
+        .map(|target| {
+            let mut path = profile_dir.clone();
+            if example_name(target).is_some() {
+                path.push("examples");
+            }
+            path.push(format!("{}.elf", target.name));
+            ((package.clone(), target.clone()), path)
+        });
 
-#[derive(Default)]
-pub struct CTRConfig {
-    name: String,
-    author: String,
-    description: String,
-    icon: image::DynamicImage,
-    target_path: PathBuf,
-    cargo_manifest_path: PathBuf,
+    find_latest_file(candidates)
 }
 
-impl CTRConfig {
-    pub fn path_3dsx(&self) -> PathBuf {
-        self.target_path.with_extension("3dsx")
+/// The name of the directory cargo puts the output of `profile` in.
+fn profile_dir(profile: &str) -> &str {
+    match profile {
+        "dev" | "test" => "debug",
+        "bench" => "release",
+        profile => profile,
     }
+}
 
-    pub fn path_smdh(&self) -> PathBuf {
-        self.target_path.with_extension("smdh")
-    }
+/// Find the most recently modified of the given files which exist.
+fn find_latest_file<T>(candidates: impl IntoIterator<Item = (T, PathBuf)>) -> Option<(T, PathBuf)> {
+    candidates
+        .into_iter()
+        .filter_map(|(item, path)| {
+            let modified = std::fs::metadata(&path).ok()?.modified().ok()?;
+            Some((modified, item, path))
+        })
+        .max_by_key(|(modified, ..)| *modified)
+        .map(|(_, item, path)| (item, path))
 }
 
-#[derive(Ord, PartialOrd, PartialEq, Eq, Debug)]
-pub struct CommitDate {
-    year: i32,
-    month: i32,
-    day: i32,
+/// The icon provided by libctru, used when the package has no icon of its own.
+fn default_icon_path(devkitpro: &Path) -> PathBuf {
+    devkitpro.join("libctru").join("default_icon.png")
 }
 
-impl CommitDate {
-    fn parse(date: &str) -> Option<Self> {
-        let mut iter = date.split('-');
+/// Builds the smdh using `cytryna` library.
+pub fn build_smdh(config: &CTRConfig) {
+    if has_transparency(&config.icon) {
+        eprintln!(
+            "warning: the icon has transparent pixels, but SMDH icons don't support transparency. \
+            Use an icon with a solid background to control how it looks."
+        );
+    }
 
-        let year = iter.next()?.parse().ok()?;
-        let month = iter.next()?.parse().ok()?;
-        let day = iter.next()?.parse().ok()?;
+    let (description, truncated) = truncate_utf16(&config.description, LONG_DESC_MAX_LEN);
+    if truncated {
+        eprintln!(
+            "warning: the description is longer than {LONG_DESC_MAX_LEN} UTF-16 characters, \
+            truncating it to {description:?}"
+        );
+    }
 
-        Some(Self { year, month, day })
+    for (field, text) in [
+        ("name", &config.name),
+        ("author", &config.author),
+        ("description", &description),
+    ] {
+        let chars = undisplayable_chars(text);
+        if !chars.is_empty() {
+            let chars: Vec<String> = chars.iter().map(|c| format!("{c:?}")).collect();
+            eprintln!(
+                "warning: the {field} contains characters which the HOME menu may not \
+                display correctly: {}",
+                chars.join(", ")
+            );
+        }
     }
-}
 
-impl fmt::Display for CommitDate {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    let smdh = Smdh::builder()
+        .with_short_desc(&config.name).unwrap()
+        .with_long_desc(&description).unwrap()
+        .with_publisher(&config.author).unwrap()
+        .with_icon((&config.icon).try_into().unwrap())
+        .build().expect("SMDH building failed");
+
+    let mut smdh = smdh.as_bytes().to_vec();
+    if let Some(small_icon) = &config.small_icon {
+        // The small icon is otherwise downscaled from the large one
+        smdh[SMALL_ICON_OFFSET..][..SMALL_ICON_BYTES].copy_from_slice(&encode_icon(small_icon));
     }
+    write_network_settings(&mut smdh, &config.network);
+
+    std::fs::write(config.path_smdh(), smdh).expect("Failed to write SMDH data");
 }
 
-const MINIMUM_COMMIT_DATE: CommitDate = CommitDate {
-    year: 2023,
-    month: 5,
+/// The distinct characters of `text` which the HOME menu may not display.
+/// Its font covers Latin-1 on every system, but other characters depend on
+/// the system's region, if they are displayed at all.
+fn undisplayable_chars(text: &str) -> Vec<char> {
+    let mut chars: Vec<char> = text
+        .chars()
+        .filter(|c| !matches!(c, '\n' | ' '..='~' | '\u{A0}'..='\u{FF}'))
+        .collect();
+    chars.sort_unstable();
+    chars.dedup();
+    chars
+}
+
+/// Maximum length of the description in an SMDH, in UTF-16 code units.
+const LONG_DESC_MAX_LEN: usize = 128;
+
+/// Truncate `text` to at most `max_len` UTF-16 code units, without splitting
+/// a character. Returns whether it had to be truncated.
+fn truncate_utf16(text: &str, max_len: usize) -> (String, bool) {
+    let mut len = 0;
+    let truncated: String = text
+        .chars()
+        .take_while(|c| {
+            len += c.len_utf16();
+            len <= max_len
+        })
+        .collect();
+
+    let was_truncated = truncated.len() < text.len();
+    (truncated, was_truncated)
+}
+
+/// Expand `{git_hash}` and `{date}` in a `--desc-suffix` template. If there
+/// is no git hash, it is left out.
+pub(crate) fn expand_desc_suffix(template: &str, git_hash: Option<&str>, date: &str) -> String {
+    let expanded = template
+        .replace("{git_hash}", git_hash.unwrap_or_default())
+        .replace("{date}", date);
+    expanded.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// The abbreviated hash of the commit checked out in `dir`, if it is in a git
+/// repository and `git` is installed.
+pub(crate) fn git_hash(dir: &Path) -> Option<String> {
+    let output = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .current_dir(dir)
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+
+    let hash = String::from_utf8(output.stdout).ok()?;
+    Some(hash.trim().to_string())
+}
+
+/// Format the UTC date of `time` as `YYYY-MM-DD`.
+pub(crate) fn utc_date(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let days = (secs / 86400) as i64;
+
+    // Convert days since the epoch to a civil date, from
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!("{year:04}-{month:02}-{day:02}")
+}
+
+/// Offsets of the match-maker ID, match-maker BIT ID and CEC ID in an SMDH.
+const MATCH_MAKER_ID_OFFSET: usize = 0x201C;
+const MATCH_MAKER_BIT_ID_OFFSET: usize = 0x2020;
+const CEC_ID_OFFSET: usize = 0x2034;
+
+/// Write the IDs from `network` to their fields in `smdh`.
+fn write_network_settings(smdh: &mut [u8], network: &NetworkMetadata) {
+    smdh[MATCH_MAKER_ID_OFFSET..][..4].copy_from_slice(&network.match_maker_id.to_le_bytes());
+    smdh[MATCH_MAKER_BIT_ID_OFFSET..][..8]
+        .copy_from_slice(&network.match_maker_bit_id.to_le_bytes());
+    smdh[CEC_ID_OFFSET..][..4].copy_from_slice(&network.cec_id.to_le_bytes());
+}
+
+/// Size of an SMDH file, in bytes.
+const SMDH_SIZE: u64 = 0x36C0;
+
+/// Check that the file at `path` exists and looks like an SMDH, i.e. it has
+/// the size of one and starts with the `SMDH` magic.
+pub fn check_smdh(path: &Path) -> Result<(), String> {
+    let mut magic = [0; 4];
+    let size = std::fs::File::open(path)
+        .and_then(|mut file| {
+            file.read_exact(&mut magic)?;
+            Ok(file.metadata()?.len())
+        })
+        .map_err(|e| format!("Could not read SMDH {}: {e}", path.display()))?;
+
+    if &magic != b"SMDH" || size != SMDH_SIZE {
+        return Err(format!("{} is not an SMDH file", path.display()));
+    }
+    Ok(())
+}
+
+/// Width and height of the small icon in an SMDH, in pixels.
+const SMALL_ICON_SIZE: u32 = 24;
+
+/// Offset of the small icon in an SMDH.
+const SMALL_ICON_OFFSET: usize = 0x2040;
+
+/// Size of the small icon in an SMDH, with 2 bytes per pixel.
+const SMALL_ICON_BYTES: usize = (SMALL_ICON_SIZE * SMALL_ICON_SIZE * 2) as usize;
+
+/// Size of the large icon in an SMDH.
+const LARGE_ICON_SIZE: u32 = 48;
+
+/// Load the icon at `path`. SVG icons are rasterized to `size`x`size`, other
+/// images are loaded at their own size.
+fn load_icon(path: &Path, size: u32) -> Result<image::DynamicImage, String> {
+    if !is_svg(path) {
+        return image::open(path).map_err(|e| e.to_string());
+    }
+
+    let icon = rasterize_svg(path, size)?;
+    if icon.width() != size || icon.height() != size {
+        return Err(format!(
+            "rasterized to {}x{} instead of {size}x{size}",
+            icon.width(),
+            icon.height()
+        ));
+    }
+    Ok(icon)
+}
+
+/// Whether the image at `path` is an SVG, from its extension or, without
+/// one, from its contents.
+fn is_svg(path: &Path) -> bool {
+    if let Some(extension) = path.extension() {
+        return extension.eq_ignore_ascii_case("svg");
+    }
+
+    let mut start = Vec::new();
+    let read = std::fs::File::open(path).and_then(|file| file.take(1024).read_to_end(&mut start));
+    read.is_ok() && start.windows(4).any(|window| window == b"<svg")
+}
+
+/// Render the SVG at `path` to a `size`x`size` image, scaled to fit and centered.
+#[cfg(feature = "svg")]
+fn rasterize_svg(path: &Path, size: u32) -> Result<image::DynamicImage, String> {
+    use resvg::{tiny_skia, usvg};
+
+    let data = std::fs::read(path).map_err(|e| e.to_string())?;
+    let tree =
+        usvg::Tree::from_data(&data, &usvg::Options::default()).map_err(|e| e.to_string())?;
+
+    let (width, height) = (tree.size().width(), tree.size().height());
+    let scale = size as f32 / width.max(height);
+    let transform = tiny_skia::Transform::from_row(
+        scale,
+        0.0,
+        0.0,
+        scale,
+        (size as f32 - width * scale) / 2.0,
+        (size as f32 - height * scale) / 2.0,
+    );
+
+    let mut pixmap = tiny_skia::Pixmap::new(size, size).ok_or("invalid icon size")?;
+    resvg::render(&tree, transform, &mut pixmap.as_mut());
+
+    let pixels = pixmap
+        .pixels()
+        .iter()
+        .flat_map(|pixel| {
+            let color = pixel.demultiply();
+            [color.red(), color.green(), color.blue(), color.alpha()]
+        })
+        .collect();
+    let icon = image::RgbaImage::from_raw(size, size, pixels).ok_or("invalid rasterized icon")?;
+    Ok(icon.into())
+}
+
+#[cfg(not(feature = "svg"))]
+fn rasterize_svg(_path: &Path, _size: u32) -> Result<image::DynamicImage, String> {
+    Err(String::from(
+        "SVG icons are only supported if cargo-3ds is installed with the `svg` feature",
+    ))
+}
+
+/// Resize the small icon loaded from `path` to 24x24 if it has another size.
+fn fit_small_icon(icon: image::DynamicImage, path: &Path) -> image::DynamicImage {
+    if icon.width() == SMALL_ICON_SIZE && icon.height() == SMALL_ICON_SIZE {
+        return icon;
+    }
+
+    eprintln!(
+        "warning: small icon {} is {}x{}, resizing it to {SMALL_ICON_SIZE}x{SMALL_ICON_SIZE}",
+        path.display(),
+        icon.width(),
+        icon.height(),
+    );
+    icon.resize_exact(
+        SMALL_ICON_SIZE,
+        SMALL_ICON_SIZE,
+        image::imageops::FilterType::Lanczos3,
+    )
+}
+
+/// Encode `icon` as the 3DS expects, in RGB565 with the pixels of each 8x8
+/// tile in Z-order.
+fn encode_icon(icon: &image::DynamicImage) -> Vec<u8> {
+    let icon = icon.to_rgb8();
+    let mut data = vec![0; (icon.width() * icon.height() * 2) as usize];
+
+    for (x, y, pixel) in icon.enumerate_pixels() {
+        let tile = (y / 8) * (icon.width() / 8) + x / 8;
+        // Interleave the bits of the coordinates in the tile, starting with x
+        let (x, y) = (x % 8, y % 8);
+        let in_tile = (0..3).fold(0, |index, bit| {
+            index | ((x >> bit) & 1) << (2 * bit) | ((y >> bit) & 1) << (2 * bit + 1)
+        });
+        let index = (tile * 64 + in_tile) as usize;
+
+        let [r, g, b] = pixel.0.map(u16::from);
+        let rgb565 = (r >> 3) << 11 | (g >> 2) << 5 | b >> 3;
+        data[index * 2..][..2].copy_from_slice(&rgb565.to_le_bytes());
+    }
+
+    data
+}
+
+/// Whether `icon` has any pixels which aren't fully opaque.
+fn has_transparency(icon: &image::DynamicImage) -> bool {
+    icon.color().has_alpha() && icon.to_rgba8().pixels().any(|pixel| pixel[3] < u8::MAX)
+}
+
+/// Builds the 3dsx using `3dsxtool`, embedding the given SMDH file if any.
+/// This will fail if `3dsxtool` is not within the running directory or in a directory found in $PATH
+///
+/// With `--strict`, a `RomFS` larger than its configured limit is an error
+/// instead of a warning. With `--quiet`, only warnings and errors are printed.
+pub fn build_3dsx(config: &CTRConfig, smdh: Option<&Path>, build: &Build) {
+    let Build {
+        verbose,
+        quiet,
+        strict,
+        romfs_compress,
+        ..
+    } = *build;
+    let mut command = Command::new("3dsxtool");
+    command.arg(&config.target_path).arg(config.path_3dsx());
+
+    if let Some(smdh) = smdh {
+        command.arg(path_arg("--smdh", smdh));
+    }
+
+    // If romfs directory exists, automatically include it
+    let (romfs_paths, is_default_romfs) = get_romfs_paths(config);
+    let romfs_paths = match romfs_paths.as_slice() {
+        [romfs_path] if is_default_romfs && !romfs_path.is_dir() => &[],
+        romfs_paths => romfs_paths,
+    };
+
+    if let Some(missing) = romfs_paths.iter().find(|path| !path.is_dir()) {
+        eprintln!("Could not find configured RomFS dir: {}", missing.display());
+        process::exit(1);
+    }
+    let exclude = romfs_exclude(config);
+    check_romfs_size(config, romfs_paths, &exclude, quiet, strict);
+
+    let romfs_compress =
+        romfs_compress || package_settings(&config.cargo_manifest_path).romfs_compress;
+    if romfs_compress && !romfs_paths.is_empty() {
+        if tool_supports_flag("3dsxtool", ROMFS_COMPRESS_FLAG) {
+            command.arg(ROMFS_COMPRESS_FLAG);
+        } else {
+            let level = if strict { "error" } else { "warning" };
+            eprintln!(
+                "{level}: RomFS compression was requested, but the installed 3dsxtool \
+                doesn't support `{ROMFS_COMPRESS_FLAG}`, so the RomFS is not compressed."
+            );
+            if strict {
+                process::exit(1);
+            }
+        }
+    }
+
+    let merged_romfs = match romfs_paths {
+        [] => None,
+        [romfs_path] if exclude.is_empty() => {
+            command.arg(path_arg("--romfs", romfs_path));
+            None
+        }
+        romfs_paths => {
+            // 3dsxtool only accepts a single RomFS directory and embeds all of
+            // it, so combine them first, without the excluded files
+            let merged_path = config.path_romfs();
+            let excluded =
+                merge_romfs_dirs(romfs_paths, &merged_path, &exclude).unwrap_or_else(|e| {
+                    eprintln!("{e}");
+                    remove_merged_romfs(&merged_path);
+                    process::exit(1);
+                });
+            if verbose && !exclude.is_empty() {
+                eprintln!("Excluded {excluded} files from the RomFS");
+            }
+
+            command.arg(path_arg("--romfs", &merged_path));
+            Some(merged_path)
+        }
+    };
+
+    if verbose {
+        print_tool_version("3dsxtool", "--help");
+        print_command(&command);
+    }
+
+    // Keep stdout for the path printed with `--print-3dsx-path` and for the
+    // JSON messages
+    command
+        .stdin(Stdio::inherit())
+        .stdout(if build.print_3dsx_path || build.json_messages {
+            Stdio::from(io::stderr())
+        } else {
+            Stdio::inherit()
+        })
+        .stderr(Stdio::inherit());
+
+    // The merged RomFS is removed even if 3dsxtool can't be run
+    let status = command.spawn().and_then(|mut process| process.wait());
+    if let Some(merged_path) = merged_romfs {
+        remove_merged_romfs(&merged_path);
+    }
+
+    let status = status.unwrap_or_else(|e| {
+        report_spawn_error(&command, &e);
+        process::exit(1);
+    });
+    if !status.success() {
+        process::exit(status.code().unwrap_or(1));
+    }
+
+    if !quiet {
+        let path_3dsx = config.path_3dsx();
+        let (size, romfs_size) = size_3dsx(&path_3dsx)
+            .unwrap_or_else(|e| panic!("Could not read {}: {e}", path_3dsx.display()));
+        match romfs_size {
+            Some(romfs_size) => eprintln!(
+                "Built {} ({}, including a {} RomFS)",
+                path_3dsx.display(),
+                format_size(size),
+                format_size(romfs_size)
+            ),
+            None => eprintln!("Built {} ({})", path_3dsx.display(), format_size(size)),
+        }
+    }
+}
+
+/// Size of the `.3dsx` header including the extended header, which has the
+/// offsets of the SMDH and `RomFS` appended after the executable.
+const EXTENDED_3DSX_HEADER_SIZE: usize = 44;
+
+/// The size of the `.3dsx` at `path`, and of its embedded `RomFS` if it has one.
+fn size_3dsx(path: &Path) -> io::Result<(u64, Option<u64>)> {
+    let file = std::fs::File::open(path)?;
+    let size = file.metadata()?.len();
+
+    let mut header = Vec::new();
+    file.take(EXTENDED_3DSX_HEADER_SIZE as u64)
+        .read_to_end(&mut header)?;
+    Ok((size, embedded_romfs_size(&header, size)))
+}
+
+/// The size of the `RomFS` embedded in a `.3dsx` of `file_size` bytes with
+/// `header`. The `RomFS` is the last part of the file, starting at the offset
+/// given in the extended header.
+fn embedded_romfs_size(header: &[u8], file_size: u64) -> Option<u64> {
+    if header.len() < EXTENDED_3DSX_HEADER_SIZE || !header.starts_with(b"3DSX") {
+        return None;
+    }
+
+    let header_size = u16::from_le_bytes([header[4], header[5]]);
+    if usize::from(header_size) < EXTENDED_3DSX_HEADER_SIZE {
+        return None;
+    }
+
+    let romfs_offset = u64::from(u32::from_le_bytes(header[40..44].try_into().unwrap()));
+    (romfs_offset != 0 && romfs_offset < file_size).then(|| file_size - romfs_offset)
+}
+
+/// A message describing the files built by `cargo-3ds`, printed along with
+/// cargo's own messages when a JSON `--message-format` is used.
+#[derive(Serialize, Debug, PartialEq)]
+struct ArtifactMessage {
+    reason: &'static str,
+    #[serde(rename = "3dsx")]
+    path_3dsx: String,
+    smdh: Option<String>,
+}
+
+impl ArtifactMessage {
+    fn new(config: &CTRConfig, smdh: Option<&Path>) -> Self {
+        Self {
+            reason: "cargo-3ds-artifact",
+            path_3dsx: config.path_3dsx().to_string_lossy().into_owned(),
+            smdh: smdh.map(|smdh| smdh.to_string_lossy().into_owned()),
+        }
+    }
+}
+
+/// Print an [`ArtifactMessage`] for the built `.3dsx` and SMDH (if any) to stdout.
+pub fn print_artifact_message(config: &CTRConfig, smdh: Option<&Path>) {
+    let message = serde_json::to_string(&ArtifactMessage::new(config, smdh))
+        .expect("Failed to serialize artifact message");
+    println!("{message}");
+}
+
+/// Print the resolved settings of `config` to stdout, to check where they come from.
+pub fn print_config(config: &CTRConfig) {
+    let (romfs_paths, _) = get_romfs_paths(config);
+    let romfs = romfs_paths
+        .iter()
+        .filter(|path| path.is_dir())
+        .map(|path| path.display().to_string())
+        .collect::<Vec<_>>();
+
+    println!("name: {}", config.name);
+    println!("author: {}", config.author);
+    println!("description: {}", config.description);
+    match &config.icon_path {
+        Some(icon_path) => println!("icon: {}", icon_path.display()),
+        None => println!("icon: (not from a file)"),
+    }
+    println!("elf: {}", config.target_path.display());
+    println!("manifest: {}", config.cargo_manifest_path.display());
+    if romfs.is_empty() {
+        println!("romfs: (none)");
+    } else {
+        println!("romfs: {}", romfs.join(", "));
+    }
+}
+
+/// Build an `--option=path` argument, without any lossy conversion of the path.
+fn path_arg(option: &str, path: &Path) -> OsString {
+    let mut arg = OsString::from(option);
+    arg.push("=");
+    arg.push(path);
+    arg
+}
+
+/// Spawn a devkitPro tool like `3dsxtool`, exiting with an explanation if it
+/// couldn't be started.
+fn spawn_tool(command: &mut Command) -> Child {
+    command.spawn().unwrap_or_else(|e| {
+        report_spawn_error(command, &e);
+        process::exit(1);
+    })
+}
+
+/// Print the error `e` from running the devkitPro tool of `command`.
+fn report_spawn_error(command: &Command, e: &io::Error) {
+    let program = command.get_program().to_string_lossy();
+    if e.kind() == io::ErrorKind::NotFound {
+        eprintln!(
+            "Could not find `{program}`. Make sure devkitPro's tools are installed \
+            and in $PATH (usually in `$DEVKITPRO/tools/bin`)."
+        );
+    } else {
+        eprintln!("Failed to run `{program}`: {e}");
+    }
+}
+
+/// The option of 3dsxtool forks which compress the RomFS with LZ compression.
+/// devkitPro's 3dsxtool doesn't have it.
+const ROMFS_COMPRESS_FLAG: &str = "--romfs-compress";
+
+/// Whether `program` lists `flag` in the output of its `--help`.
+fn tool_supports_flag(program: &str, flag: &str) -> bool {
+    Command::new(program)
+        .arg("--help")
+        .stdin(Stdio::null())
+        .output()
+        .is_ok_and(|output| {
+            help_mentions_flag(&String::from_utf8_lossy(&output.stdout), flag)
+                || help_mentions_flag(&String::from_utf8_lossy(&output.stderr), flag)
+        })
+}
+
+/// Whether `flag` is listed in a tool's help, e.g. as `--flag`, `[--flag]` or `--flag=VALUE`.
+fn help_mentions_flag(help: &str, flag: &str) -> bool {
+    help.split(|c: char| c.is_whitespace() || "[]|,".contains(c))
+        .any(|word| word.split('=').next() == Some(flag))
+}
+
+/// Print the version of a devkitPro tool, found in its output when run with
+/// `arg`. Tools from different devkitPro releases may handle arguments
+/// differently, so this helps to diagnose arguments not reaching the executable.
+fn print_tool_version(program: &str, arg: &str) {
+    let version = Command::new(program)
+        .arg(arg)
+        .stdin(Stdio::null())
+        .output()
+        .ok()
+        .and_then(|output| {
+            parse_tool_version(&String::from_utf8_lossy(&output.stdout))
+                .or_else(|| parse_tool_version(&String::from_utf8_lossy(&output.stderr)))
+        });
+
+    match version {
+        Some(version) => eprintln!("Using {program} {version}"),
+        None => eprintln!("Using {program} (unknown version)"),
+    }
+}
+
+/// Find the first version number, like `1.2` or `v1.2.3`, in a tool's output.
+fn parse_tool_version(output: &str) -> Option<String> {
+    output.split_whitespace().find_map(|word| {
+        let version = word.trim_start_matches('v').trim_end_matches([',', ')']);
+        let mut parts = version.split('.');
+        let is_number = |part: &str| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit());
+
+        let valid = version.contains('.') && parts.all(is_number);
+        valid.then(|| version.to_string())
+    })
+}
+
+/// Format a size in bytes in a human-readable way, e.g. `1.5 MiB`.
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = UNITS[0];
+    for next_unit in &UNITS[1..] {
+        if size < 1024.0 {
+            break;
+        }
+        size /= 1024.0;
+        unit = next_unit;
+    }
+
+    format!("{size:.1} {unit}")
+}
+
+/// The default limit for the total size of the `RomFS` directories, in MiB.
+/// Anything larger is most likely a misconfigured `romfs_dir`.
+const DEFAULT_ROMFS_SIZE_LIMIT: u64 = 256;
+
+/// Print the size of each `RomFS` directory (unless `quiet`), and warn (or exit,
+/// if `strict`) if their total size exceeds `romfs_size_limit` from the manifest.
+/// Files left out by `exclude` are not counted.
+fn check_romfs_size(
+    config: &CTRConfig,
+    romfs_paths: &[PathBuf],
+    exclude: &RomfsExclude,
+    quiet: bool,
+    strict: bool,
+) {
+    let mut total_size = 0;
+    for romfs_path in romfs_paths {
+        let size = dir_size(romfs_path, exclude).unwrap_or_else(|e| {
+            eprintln!("Could not read RomFS dir {}: {e}", romfs_path.display());
+            process::exit(1);
+        });
+        if !quiet {
+            eprintln!(
+                "Adding RomFS from {} ({})",
+                romfs_path.display(),
+                format_size(size)
+            );
+        }
+        total_size += size;
+    }
+
+    let limit_mib = package_settings(&config.cargo_manifest_path)
+        .romfs_size_limit
+        .unwrap_or(DEFAULT_ROMFS_SIZE_LIMIT);
+    let limit = limit_mib.saturating_mul(1024 * 1024);
+
+    if total_size > limit {
+        let level = if strict { "error" } else { "warning" };
+        eprintln!(
+            "{level}: the RomFS is {}, which is more than the limit of {limit_mib} MiB. \
+            Check that `romfs_dir` doesn't include unintended files, or raise \
+            `romfs_size_limit` (in MiB) in the manifest.",
+            format_size(total_size)
+        );
+
+        if strict {
+            process::exit(1);
+        }
+    }
+}
+
+/// Print the files in the `RomFS` of the package (or its example) selected by
+/// the options of `build`, with their sizes. Files which are usually included
+/// by mistake, like `.DS_Store` or editor backups, are pointed out.
+pub fn list_romfs(build: &Build) {
+    let package = selected_package(
+        cargo_metadata(&build.metadata_args()),
+        build.selected_package_name().as_deref(),
+        build.selected_manifest_path().as_deref(),
+    );
+    let Some(package) = package else {
+        eprintln!("No package found to list the RomFS of, use `--manifest-path` to select one");
+        process::exit(1);
+    };
+    let mut config = CTRConfig::builder()
+        .cargo_manifest_path(package.manifest_path.as_std_path())
+        .features(enabled_features(package, &build.requested_features()));
+    if let Some(example) = build.selected_example() {
+        config = config.example(example);
+    }
+
+    let config = config.build();
+    let exclude = romfs_exclude(&config);
+    let (romfs_paths, is_default) = get_romfs_paths(&config);
+    let mut total_size = 0;
+    let mut excluded = 0;
+    for romfs_path in romfs_paths {
+        if !romfs_path.is_dir() {
+            if is_default {
+                eprintln!("No RomFS found at {}", romfs_path.display());
+                return;
+            }
+            eprintln!(
+                "Could not find configured RomFS dir: {}",
+                romfs_path.display()
+            );
+            process::exit(1);
+        }
+
+        let files = romfs_files(&romfs_path).unwrap_or_else(|e| {
+            eprintln!("Could not read RomFS dir {}: {e}", romfs_path.display());
+            process::exit(1);
+        });
+        println!("{}:", romfs_path.display());
+        for (path, size) in files {
+            if exclude.excludes_file(&path) {
+                excluded += 1;
+                continue;
+            }
+            let note = if is_unintended_file(&path) {
+                "  (probably unintended)"
+            } else {
+                ""
+            };
+            println!("  {:>10}  {}{note}", format_size(size), path.display());
+            total_size += size;
+        }
+    }
+    println!("Total: {}", format_size(total_size));
+    if excluded > 0 {
+        println!("Excluded by `romfs_exclude`: {excluded} files");
+    }
+}
+
+/// The files in `dir` and its subdirectories, relative to `dir` and sorted,
+/// with their sizes. Like when merging `RomFS` directories, symlinks to files
+/// are included but symlinks to directories are not followed.
+fn romfs_files(dir: &Path) -> io::Result<Vec<(PathBuf, u64)>> {
+    fn visit(root: &Path, dir: &Path, files: &mut Vec<(PathBuf, u64)>) -> io::Result<()> {
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            let mut metadata = std::fs::symlink_metadata(&path)?;
+            if metadata.is_symlink() {
+                match std::fs::metadata(&path) {
+                    Ok(target) if target.is_file() => metadata = target,
+                    _ => continue,
+                }
+            }
+
+            if metadata.is_dir() {
+                visit(root, &path, files)?;
+            } else {
+                files.push((path.strip_prefix(root).unwrap().to_owned(), metadata.len()));
+            }
+        }
+        Ok(())
+    }
+
+    let mut files = Vec::new();
+    visit(dir, dir, &mut files)?;
+    files.sort();
+    Ok(files)
+}
+
+/// Whether a file in the `RomFS` is one that is usually there by mistake, like
+/// OS metadata, version control files or editor backups.
+fn is_unintended_file(path: &Path) -> bool {
+    const NAMES: &[&str] = &[".DS_Store", "Thumbs.db", "desktop.ini"];
+    const DIRS: &[&str] = &[".git", ".svn", ".hg", "__MACOSX"];
+    const SUFFIXES: &[&str] = &["~", ".swp", ".swo", ".tmp", ".bak"];
+
+    let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+        return false;
+    };
+    NAMES.contains(&name)
+        || name.starts_with(".#")
+        || SUFFIXES.iter().any(|suffix| name.ends_with(suffix))
+        || path
+            .components()
+            .any(|component| DIRS.iter().any(|dir| component.as_os_str() == *dir))
+}
+
+/// The total size of the files in `dir` and its subdirectories, except those
+/// left out by `exclude`.
+fn dir_size(dir: &Path, exclude: &RomfsExclude) -> io::Result<u64> {
+    Ok(romfs_files(dir)?
+        .into_iter()
+        .filter(|(path, _)| !exclude.excludes_file(path))
+        .map(|(_, size)| size)
+        .sum())
+}
+
+/// Patterns from `romfs_exclude` of files to leave out of the `RomFS`.
+///
+/// Like in a `.gitignore`, a pattern without `/` matches a file or directory
+/// with that name anywhere, and other patterns match the path from the root
+/// of the `RomFS` directory.
+#[derive(Debug, Default)]
+struct RomfsExclude {
+    /// The patterns, and whether each matches the whole path.
+    patterns: Vec<(glob::Pattern, bool)>,
+}
+
+impl RomfsExclude {
+    fn new(patterns: &[String]) -> Result<Self, String> {
+        let patterns = patterns
+            .iter()
+            .map(|pattern| {
+                glob::Pattern::new(pattern.trim_start_matches('/'))
+                    .map(|glob| (glob, pattern.contains('/')))
+                    .map_err(|e| format!("Invalid `romfs_exclude` pattern {pattern:?}: {e}"))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self { patterns })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+
+    /// Whether the file at `relative` is excluded, either itself or through
+    /// one of the directories containing it.
+    fn excludes_file(&self, relative: &Path) -> bool {
+        relative
+            .ancestors()
+            .filter(|path| !path.as_os_str().is_empty())
+            .any(|path| self.matches(path))
+    }
+
+    /// Whether the file or directory at `relative`, from the root of a
+    /// `RomFS` directory, is excluded.
+    fn matches(&self, relative: &Path) -> bool {
+        let options = glob::MatchOptions {
+            case_sensitive: true,
+            require_literal_separator: true,
+            require_literal_leading_dot: false,
+        };
+
+        self.patterns.iter().any(|(pattern, anchored)| {
+            if *anchored {
+                pattern.matches_path_with(relative, options)
+            } else {
+                relative
+                    .file_name()
+                    .is_some_and(|name| pattern.matches_path_with(Path::new(name), options))
+            }
+        })
+    }
+}
+
+/// The `romfs_exclude` patterns of the package of `config`.
+fn romfs_exclude(config: &CTRConfig) -> RomfsExclude {
+    let settings = package_settings(&config.cargo_manifest_path);
+    RomfsExclude::new(&settings.romfs_exclude).unwrap_or_else(|e| {
+        eprintln!("{e}");
+        process::exit(1);
+    })
+}
+
+/// Combine the contents of several `RomFS` directories into `dest`, which is
+/// (re)created from scratch, leaving out the files matched by `exclude`.
+/// Files are hard linked where possible, and copied otherwise. Symlinks to
+/// files are copied, but symlinks to directories are not followed.
+///
+/// Returns the number of files excluded, or an error if the same path is
+/// provided by more than one directory, other than as a directory in each.
+fn merge_romfs_dirs(
+    sources: &[PathBuf],
+    dest: &Path,
+    exclude: &RomfsExclude,
+) -> Result<usize, String> {
+    if dest.exists() {
+        std::fs::remove_dir_all(dest)
+            .map_err(|e| format!("Failed to remove {}: {e}", dest.display()))?;
+    }
+
+    let mut provided_by = HashMap::new();
+    let mut excluded = 0;
+    for source in sources {
+        merge_romfs_dir(
+            source,
+            source,
+            dest,
+            exclude,
+            &mut provided_by,
+            &mut excluded,
+        )?;
+    }
+    Ok(excluded)
+}
+
+/// The source directory of each path merged so far, relative to the root of
+/// the `RomFS`, and whether the path is a directory.
+type ProvidedBy = HashMap<PathBuf, (PathBuf, bool)>;
+
+fn merge_romfs_dir(
+    root: &Path,
+    dir: &Path,
+    dest: &Path,
+    exclude: &RomfsExclude,
+    provided_by: &mut ProvidedBy,
+    excluded: &mut usize,
+) -> Result<(), String> {
+    let dest_dir = dest.join(dir.strip_prefix(root).unwrap());
+    std::fs::create_dir_all(&dest_dir)
+        .map_err(|e| format!("Failed to create {}: {e}", dest_dir.display()))?;
+
+    let read_error = |e| format!("Could not read RomFS dir {}: {e}", dir.display());
+    for entry in std::fs::read_dir(dir).map_err(read_error)? {
+        let entry = entry.map_err(read_error)?;
+        let path = entry.path();
+        let relative = path.strip_prefix(root).unwrap().to_path_buf();
+
+        let file_type = entry.file_type().map_err(read_error)?;
+        let is_dir = file_type.is_dir();
+        if exclude.matches(&relative) {
+            *excluded += if is_dir {
+                romfs_files(&path).map_or(0, |files| files.len())
+            } else {
+                1
+            };
+            continue;
+        }
+
+        if file_type.is_symlink() && !path.is_file() {
+            eprintln!(
+                "warning: not following the symlink {} in the RomFS",
+                path.display()
+            );
+            continue;
+        }
+
+        // Directories provided by several sources are merged, anything else
+        // would replace another file
+        match provided_by.get(&relative) {
+            Some((_, true)) if is_dir => {}
+            Some((other, _)) => {
+                return Err(format!(
+                    "RomFS file {} is provided by both {} and {}",
+                    relative.display(),
+                    other.display(),
+                    root.display()
+                ));
+            }
+            None => {
+                provided_by.insert(relative.clone(), (root.to_path_buf(), is_dir));
+            }
+        }
+
+        if is_dir {
+            merge_romfs_dir(root, &path, dest, exclude, provided_by, excluded)?;
+            continue;
+        }
+
+        // A hard link to a symlink would be a symlink, relative to the wrong directory
+        let target = dest.join(&relative);
+        let linked = !file_type.is_symlink() && std::fs::hard_link(&path, &target).is_ok();
+        if !linked {
+            std::fs::copy(&path, &target)
+                .map_err(|e| format!("Failed to copy {}: {e}", path.display()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Remove the directory created by [`merge_romfs_dirs`] at `path`.
+fn remove_merged_romfs(path: &Path) {
+    if let Err(e) = std::fs::remove_dir_all(path) {
+        eprintln!("Failed to clean up {}: {e}", path.display());
+    }
+}
+
+/// Run a post-build command with the system shell, in the package's directory.
+/// The paths of the built files are passed in the `CARGO_3DS_3DSX`, `CARGO_3DS_SMDH`
+/// and `CARGO_3DS_ELF` environment variables.
+pub fn run_post_build(config: &CTRConfig, post_build: &str, verbose: bool) {
+    let mut command = if cfg!(windows) {
+        let mut command = Command::new("cmd");
+        command.arg("/C");
+        command
+    } else {
+        let mut command = Command::new("sh");
+        command.arg("-c");
+        command
+    };
+
+    command
+        .arg(post_build)
+        .current_dir(config.cargo_manifest_path.parent().unwrap())
+        .env("CARGO_3DS_3DSX", config.path_3dsx())
+        .env("CARGO_3DS_SMDH", config.path_smdh())
+        .env("CARGO_3DS_ELF", &config.target_path)
+        .stdin(Stdio::inherit())
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::inherit());
+
+    if verbose {
+        print_command(&command);
+    }
+
+    let status = command
+        .status()
+        .unwrap_or_else(|e| panic!("Failed to run post-build command: {e}"));
+
+    if !status.success() {
+        eprintln!("Post-build command failed: {status}");
+        process::exit(status.code().unwrap_or(1));
+    }
+}
+
+/// Link the generated 3dsx to a 3ds to execute and test using `3dslink`.
+/// This will fail if `3dslink` is not within the running directory or in a directory found in $PATH
+pub fn link(config: &CTRConfig, run_args: &Run, verbose: bool) {
+    // A `.3dsx` sent with `--link-only` has no package
+    let settings = if config.cargo_manifest_path.as_os_str().is_empty() {
+        Cargo3dsMetadata::default()
+    } else {
+        package_settings(&config.cargo_manifest_path)
+    };
+
+    // The output of the executable (with `--server`) may contain escape codes
+    let strip_colors = run_args.build_args.color.resolve() == ColorChoice::Never;
+
+    let mut command = Command::new("3dslink");
+    command
+        .arg(config.path_3dsx())
+        .args(run_args.get_3dslink_args(config, &settings))
+        .stdin(Stdio::inherit())
+        .stdout(if strip_colors {
+            Stdio::piped()
+        } else {
+            Stdio::inherit()
+        })
+        .stderr(Stdio::inherit());
+
+    if strip_colors {
+        command.env("NO_COLOR", "1");
+    }
+
+    if verbose {
+        print_tool_version("3dslink", "--version");
+        print_command(&command);
+    }
+
+    let mut process = spawn_tool(&mut command);
+    let copy_output = process
+        .stdout
+        .take()
+        .map(|stdout| thread::spawn(move || copy_without_escape_codes(stdout, io::stdout())));
+
+    let (status, interrupted) = if run_args.stdio_over_network {
+        eprintln!("Showing output from the device, press Ctrl-C to stop");
+        wait_ignoring_interrupt(&mut process)
+    } else {
+        (process.wait().unwrap(), false)
+    };
+
+    if let Some(copy_output) = copy_output {
+        if let Err(e) = copy_output.join().unwrap() {
+            eprintln!("Failed to copy output of 3dslink: {e}");
+        }
+    }
+
+    if interrupted {
+        return;
+    }
+
+    if !status.success() {
+        process::exit(status.code().unwrap_or(1));
+    }
+}
+
+/// Run the `.3dsx` of `config` with the simulator `program`, copying its
+/// output to stdout. Exits with an error if the tests it runs fail, according
+/// to libtest's summary, or if no summary is printed and the simulator fails.
+///
+/// The simulator is stopped if it is still running after `timeout`, which is
+/// only an error if no summary was printed by then.
+pub fn simulate(config: &CTRConfig, program: &str, timeout: Duration, verbose: bool) {
+    let mut command = Command::new(program);
+    command
+        .arg(config.path_3dsx())
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit());
+
+    if verbose {
+        print_command(&command);
+    }
+
+    let mut process = command.spawn().unwrap_or_else(|e| {
+        if e.kind() == io::ErrorKind::NotFound {
+            eprintln!(
+                "Could not find the simulator `{program}`. Pass its path with \
+                `--simulator=PROGRAM` or set CARGO_3DS_SIMULATOR."
+            );
+        } else {
+            eprintln!("Failed to run `{program}`: {e}");
+        }
+        process::exit(1);
+    });
+
+    let (sender, receiver) = mpsc::channel();
+    let stdout = BufReader::new(process.stdout.take().unwrap());
+    thread::spawn(move || {
+        for line in stdout.lines().map_while(Result::ok) {
+            if sender.send(line).is_err() {
+                break;
+            }
+        }
+    });
+
+    let deadline = Instant::now() + timeout;
+    let mut passed = None;
+    let mut timed_out = false;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        match receiver.recv_timeout(remaining) {
+            Ok(line) => {
+                println!("{line}");
+                passed = merge_libtest_results(passed, libtest_result(&line));
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                timed_out = true;
+                let _ = process.kill();
+                break;
+            }
+        }
+    }
+    let status = process.wait().unwrap();
+
+    match passed {
+        Some(true) => {}
+        Some(false) => process::exit(101),
+        None if timed_out => {
+            eprintln!(
+                "`{program}` did not print test results within {} seconds",
+                timeout.as_secs()
+            );
+            process::exit(1);
+        }
+        None if status.success() => {
+            eprintln!("No test results found in the output of `{program}`");
+            process::exit(1);
+        }
+        None => process::exit(status.code().unwrap_or(1)),
+    }
+}
+
+/// Combine the results of libtest summaries so far with the result of another
+/// line of output. Once any summary reports failed tests, the result stays failed.
+fn merge_libtest_results(passed: Option<bool>, line_result: Option<bool>) -> Option<bool> {
+    match (passed, line_result) {
+        (Some(false), _) | (_, Some(false)) => Some(false),
+        (passed, line_result) => line_result.or(passed),
+    }
+}
+
+/// Whether a line of libtest's output is its summary of passed (`Some(true)`)
+/// or failed (`Some(false)`) tests.
+fn libtest_result(line: &str) -> Option<bool> {
+    let result = line.trim().strip_prefix("test result: ")?;
+    if result.starts_with("ok.") {
+        Some(true)
+    } else if result.starts_with("FAILED.") {
+        Some(false)
+    } else {
+        None
+    }
+}
+
+/// Copy `reader` to `writer` line by line, removing ANSI escape codes.
+fn copy_without_escape_codes(reader: impl Read, mut writer: impl Write) -> io::Result<()> {
+    let mut reader = BufReader::new(reader);
+    let mut line = Vec::new();
+
+    while reader.read_until(b'\n', &mut line)? > 0 {
+        writer.write_all(&strip_escape_codes(&line))?;
+        writer.flush()?;
+        line.clear();
+    }
+
+    Ok(())
+}
+
+/// Remove ANSI escape codes (like colors or cursor movements) from `text`.
+fn strip_escape_codes(text: &[u8]) -> Vec<u8> {
+    const ESC: u8 = 0x1b;
+
+    let mut stripped = Vec::with_capacity(text.len());
+    let mut bytes = text.iter().copied();
+
+    while let Some(byte) = bytes.next() {
+        if byte != ESC {
+            stripped.push(byte);
+            continue;
+        }
+
+        // Control sequences end with a byte in the range `@` to `~`,
+        // other escapes are only two bytes long
+        if bytes.next() == Some(b'[') {
+            for byte in bytes.by_ref() {
+                if (0x40..=0x7e).contains(&byte) {
+                    break;
+                }
+            }
+        }
+    }
+
+    stripped
+}
+
+/// Wait for `child` while ignoring `SIGINT`, so that Ctrl-C only stops the child.
+/// Returns whether the child was stopped by `SIGINT`.
+#[cfg(unix)]
+fn wait_ignoring_interrupt(child: &mut Child) -> (ExitStatus, bool) {
+    use std::os::unix::process::ExitStatusExt;
+
+    // The child was spawned beforehand, so it still handles `SIGINT` normally.
+    // SAFETY: this only changes the disposition of `SIGINT`, restoring it afterwards.
+    let previous = unsafe { libc::signal(libc::SIGINT, libc::SIG_IGN) };
+    let status = child.wait().unwrap();
+    unsafe { libc::signal(libc::SIGINT, previous) };
+
+    (status, status.signal() == Some(libc::SIGINT))
+}
+
+#[cfg(not(unix))]
+fn wait_ignoring_interrupt(child: &mut Child) -> (ExitStatus, bool) {
+    (child.wait().unwrap(), false)
+}
+
+/// Print the size of each section of the built executable using `arm-none-eabi-size`.
+/// If it can't be found, only the total size of the `.elf` file is printed.
+pub fn print_size(config: &CTRConfig, verbose: bool) {
+    let mut command = Command::new("arm-none-eabi-size");
+    command
+        .arg("-A")
+        .arg(&config.target_path)
+        .stdin(Stdio::inherit())
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::inherit());
+
+    if verbose {
+        print_command(&command);
+    }
+
+    match command.status() {
+        Ok(status) if !status.success() => process::exit(status.code().unwrap_or(1)),
+        Ok(_) => {}
+        Err(e) => {
+            eprintln!("Could not run `arm-none-eabi-size` ({e}), is devkitARM in $PATH?");
+
+            let size = std::fs::metadata(&config.target_path)
+                .unwrap_or_else(|e| panic!("Could not read {}: {e}", config.target_path.display()))
+                .len();
+            println!("{}: {size} bytes", config.target_path.display());
+        }
+    }
+}
+
+/// The port used by the Homebrew Launcher's netloader, which `3dslink` sends to.
+const NETLOADER_PORT: u16 = 17491;
+
+/// Find devices ready to receive an executable with `3dslink`, the same way
+/// `3dslink` does when no address is given: by broadcasting a UDP message
+/// and collecting the replies received within `timeout`.
+pub fn find_devices(timeout: Duration) -> io::Result<Vec<IpAddr>> {
+    // The netloader replies to the sender, so any free local port will do
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.set_broadcast(true)?;
+    socket.send_to(b"3dsboot", (Ipv4Addr::BROADCAST, NETLOADER_PORT))?;
+
+    let deadline = Instant::now() + timeout;
+    let mut devices = Vec::new();
+    let mut buf = [0; 16];
+
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            break;
+        }
+        socket.set_read_timeout(Some(remaining))?;
+
+        match socket.recv_from(&mut buf) {
+            // Our own broadcast may be received too, so only count replies
+            Ok((len, addr)) if &buf[..len] == b"boot3ds" => {
+                if !devices.contains(&addr.ip()) {
+                    devices.push(addr.ip());
+                }
+            }
+            Ok(_) => {}
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                break
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(devices)
+}
+
+/// Read the `RomFS` paths from the Cargo manifest. If unset, use the default.
+/// `romfs_dir` may either be a single directory or a list of directories,
+/// which will be merged together. Examples may override it in
+/// `[package.metadata.cargo-3ds.example.<name>]`.
+/// The returned boolean is true when the default is used.
+///
+/// The `CARGO_3DS_ROMFS` environment variable overrides all of these.
+pub fn get_romfs_paths(config: &CTRConfig) -> (Vec<PathBuf>, bool) {
+    romfs_paths_with_override(config, env::var_os(ROMFS_VAR))
+}
+
+/// Environment variable with a `RomFS` directory to use instead of the configured one.
+const ROMFS_VAR: &str = "CARGO_3DS_ROMFS";
+
+/// Like [`get_romfs_paths`], using `romfs_override` (from [`ROMFS_VAR`]) if it's set.
+fn romfs_paths_with_override(
+    config: &CTRConfig,
+    romfs_override: Option<OsString>,
+) -> (Vec<PathBuf>, bool) {
+    let manifest_path = &config.cargo_manifest_path;
+
+    let mut manifest_dir = manifest_path.clone();
+    manifest_dir.pop(); // Pop Cargo.toml
+
+    // Like a configured directory, the override must exist
+    if let Some(romfs_dir) = romfs_override.filter(|dir| !dir.is_empty()) {
+        return (vec![manifest_dir.join(romfs_dir)], false);
+    }
+
+    let settings = package_settings(manifest_path);
+
+    // Find the romfs setting and compute the paths
+    let romfs_dir = settings.romfs_dir(config.example.as_deref(), &config.features);
+    let (romfs_dirs, is_default) = match romfs_dir {
+        None => (vec!["romfs"], true),
+        Some(romfs_dirs) => (romfs_dirs, false),
+    };
+
+    let romfs_paths = romfs_dirs
+        .into_iter()
+        .map(|dir| {
+            let dir = expand_env_vars(dir, &manifest_dir).unwrap_or_else(|e| {
+                eprintln!("Could not expand `romfs_dir` {dir:?}: {e}");
+                process::exit(1);
+            });
+            manifest_dir.join(dir)
+        })
+        .collect();
+
+    (romfs_paths, is_default)
+}
+
+/// Read the post-build command from the Cargo manifest, if any.
+pub fn get_post_build_command(config: &CTRConfig) -> Option<String> {
+    package_settings(&config.cargo_manifest_path).post_build
+}
+
+/// The settings of a package for `cargo-3ds`, from `[package.metadata.cargo-3ds]`
+/// in its manifest and its `.cargo-3ds.toml`. See [`read_cargo_3ds_metadata`].
+#[derive(Deserialize, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct Cargo3dsMetadata {
+    /// The application name shown in the HOME menu.
+    pub name: Option<String>,
+    /// The application author shown in the HOME menu.
+    pub author: Option<String>,
+    /// The application description shown in the HOME menu.
+    pub description: Option<String>,
+    /// The `RomFS` directories to embed in the `.3dsx`.
+    pub romfs_dir: Option<RomfsDir>,
+    /// The limit for the total size of the `RomFS` directories, in MiB.
+    pub romfs_size_limit: Option<u64>,
+    /// Whether to compress the `RomFS`, if 3dsxtool supports it.
+    pub romfs_compress: bool,
+    /// Glob patterns of files to leave out of the `RomFS`.
+    pub romfs_exclude: Vec<String>,
+    /// The 24x24 icon, relative to the package's directory. By default it is
+    /// downscaled from the large icon.
+    pub small_icon: Option<String>,
+    /// A command to run after the `.3dsx` is built.
+    pub post_build: Option<String>,
+    /// The IDs for online and StreetPass features, from
+    /// `[package.metadata.cargo-3ds.network]`.
+    pub network: NetworkMetadata,
+    /// The IP address or hostname of the device to send executables to.
+    pub address: Option<String>,
+    /// The devkitPro libraries to link, in order, instead of only libctru.
+    pub link_libs: Option<Vec<String>>,
+    /// Additional directories to search for libraries to link, relative to
+    /// the package's directory.
+    pub lib_paths: Vec<String>,
+    /// Settings for specific examples, by name.
+    pub example: HashMap<String, ExampleMetadata>,
+    /// Settings for builds with specific features enabled, by feature name.
+    pub feature: HashMap<String, FeatureMetadata>,
+}
+
+/// The IDs stored in the SMDH for online play and StreetPass, which are zero
+/// unless an application uses these features.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(default)]
+pub struct NetworkMetadata {
+    /// The match-maker ID, used for online play.
+    pub match_maker_id: u32,
+    /// The match-maker BIT ID, used for online play.
+    pub match_maker_bit_id: u64,
+    /// The CEC (StreetPass) ID.
+    pub cec_id: u32,
+}
+
+/// Settings for a single example, from `[package.metadata.cargo-3ds.example.<name>]`.
+#[derive(Deserialize, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct ExampleMetadata {
+    /// The `RomFS` directories to embed in the example's `.3dsx` instead of
+    /// the package's.
+    pub romfs_dir: Option<RomfsDir>,
+}
+
+/// Settings for builds with a feature enabled, from
+/// `[package.metadata.cargo-3ds.feature.<name>]`.
+#[derive(Deserialize, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct FeatureMetadata {
+    /// The `RomFS` directories to embed in the `.3dsx` instead of the
+    /// package's when the feature is enabled.
+    pub romfs_dir: Option<RomfsDir>,
+}
+
+/// A `romfs_dir` setting, which may be a single directory or a list of them.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum RomfsDir {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+impl RomfsDir {
+    pub fn dirs(&self) -> &[String] {
+        match self {
+            Self::Single(dir) => std::slice::from_ref(dir),
+            Self::Multiple(dirs) => dirs,
+        }
+    }
+}
+
+impl Cargo3dsMetadata {
+    /// The directories of the `romfs_dir` setting, preferring the one for
+    /// `example` if it has one, then the ones for the enabled `features`
+    /// combined (in the order of `features`).
+    pub fn romfs_dir(&self, example: Option<&str>, features: &[String]) -> Option<Vec<&str>> {
+        let example_dir = example
+            .and_then(|example| self.example.get(example))
+            .and_then(|example| example.romfs_dir.as_ref());
+
+        let settings: Vec<&RomfsDir> = match example_dir {
+            Some(romfs_dir) => vec![romfs_dir],
+            None => {
+                let feature_dirs: Vec<_> = features
+                    .iter()
+                    .filter_map(|feature| self.feature.get(feature)?.romfs_dir.as_ref())
+                    .collect();
+                if feature_dirs.is_empty() {
+                    self.romfs_dir.iter().collect()
+                } else {
+                    feature_dirs
+                }
+            }
+        };
+
+        (!settings.is_empty()).then(|| {
+            settings
+                .into_iter()
+                .flat_map(RomfsDir::dirs)
+                .map(String::as_str)
+                .collect()
+        })
+    }
+
+    fn from_table(table: toml::value::Table) -> Result<Self, toml::de::Error> {
+        toml::Value::Table(table).try_into()
+    }
+}
+
+/// Read the settings of the package with the given manifest, exiting if they
+/// are invalid.
+fn package_settings(manifest_path: &Path) -> Cargo3dsMetadata {
+    read_cargo_3ds_metadata(manifest_path).unwrap_or_else(|e| {
+        eprintln!("{e}");
+        process::exit(1);
+    })
+}
+
+/// Name of the file next to a package's manifest which can hold the same
+/// settings as `[package.metadata.cargo-3ds]`.
+const CONFIG_FILE_NAME: &str = ".cargo-3ds.toml";
+
+/// Read the `[package.metadata.cargo-3ds]` table from a Cargo manifest, merged
+/// with the settings from the package's `.cargo-3ds.toml` if it exists.
+/// Settings in the manifest take precedence over the ones in the file.
+/// Default settings are returned if neither is set.
+pub fn read_cargo_3ds_metadata(manifest_path: &Path) -> Result<Cargo3dsMetadata, String> {
+    let manifest_str = std::fs::read_to_string(manifest_path)
+        .map_err(|e| format!("Could not open {}: {e}", manifest_path.display()))?;
+    let manifest_data: toml::Value = toml::de::from_str(&manifest_str)
+        .map_err(|e| format!("Could not parse {}: {e}", manifest_path.display()))?;
+
+    let mut metadata = manifest_data
+        .as_table()
+        .and_then(|table| table.get("package"))
+        .and_then(toml::Value::as_table)
+        .and_then(|table| table.get("metadata"))
+        .and_then(toml::Value::as_table)
+        .and_then(|table| table.get("cargo-3ds"))
+        .and_then(toml::Value::as_table)
+        .cloned()
+        .unwrap_or_default();
+
+    let config_path = manifest_path.with_file_name(CONFIG_FILE_NAME);
+    if config_path.exists() {
+        let config_str = std::fs::read_to_string(&config_path)
+            .map_err(|e| format!("Could not open {}: {e}", config_path.display()))?;
+        let config: toml::value::Table = toml::de::from_str(&config_str)
+            .map_err(|e| format!("Could not parse {}: {e}", config_path.display()))?;
+
+        merge_settings(&mut metadata, config);
+    }
+
+    Cargo3dsMetadata::from_table(metadata).map_err(|e| {
+        format!(
+            "Invalid cargo-3ds settings for {}: {e}",
+            manifest_path.display()
+        )
+    })
+}
+
+/// Add the settings of `defaults` which are not in `settings`. Tables in both,
+/// like `network` or `example.<name>`, are merged key by key in the same way.
+fn merge_settings(settings: &mut toml::value::Table, defaults: toml::value::Table) {
+    for (key, default) in defaults {
+        match (settings.get_mut(&key), default) {
+            (Some(toml::Value::Table(table)), toml::Value::Table(default)) => {
+                merge_settings(table, default);
+            }
+            (Some(_), _) => {}
+            (None, default) => {
+                settings.insert(key, default);
+            }
+        }
+    }
+}
+
+/// Expand `$VAR` and `${VAR}` in a configured path using environment variables.
+/// `CARGO_MANIFEST_DIR` is set to `manifest_dir` if not already defined.
+fn expand_env_vars(value: &str, manifest_dir: &Path) -> Result<String, String> {
+    expand_vars(value, |name| match env::var(name) {
+        Err(_) if name == "CARGO_MANIFEST_DIR" => Some(manifest_dir.to_string_lossy().into()),
+        var => var.ok(),
+    })
+}
+
+fn expand_vars(value: &str, lookup: impl Fn(&str) -> Option<String>) -> Result<String, String> {
+    let is_var_char = |c: char| c.is_ascii_alphanumeric() || c == '_';
+
+    let mut expanded = String::new();
+    let mut rest = value;
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        rest = &rest[start + 1..];
+
+        let name = if let Some(braced) = rest.strip_prefix('{') {
+            let end = braced
+                .find('}')
+                .ok_or_else(|| String::from("missing closing `}`"))?;
+            rest = &braced[end + 1..];
+            &braced[..end]
+        } else {
+            let end = rest.find(|c| !is_var_char(c)).unwrap_or(rest.len());
+            let name = &rest[..end];
+            rest = &rest[end..];
+            name
+        };
+
+        if name.is_empty() || !name.chars().all(is_var_char) {
+            return Err(format!("invalid variable name {name:?}"));
+        }
+
+        let var = lookup(name).ok_or_else(|| format!("environment variable {name} is not set"))?;
+        expanded.push_str(&var);
+    }
+    expanded.push_str(rest);
+
+    Ok(expanded)
+}
+
+#[derive(Default)]
+pub struct CTRConfig {
+    name: String,
+    author: String,
+    description: String,
+    icon: image::DynamicImage,
+    icon_path: Option<PathBuf>,
+    small_icon: Option<image::DynamicImage>,
+    network: NetworkMetadata,
+    target_path: PathBuf,
+    cargo_manifest_path: PathBuf,
+    example: Option<String>,
+    features: Vec<String>,
+    output_name: Option<String>,
+    version: Option<Version>,
+}
+
+impl CTRConfig {
+    /// Create a builder for a config, to use [`build_smdh`], [`build_3dsx`] and
+    /// other functions on an executable not built by [`run_cargo`].
+    pub fn builder() -> CTRConfigBuilder {
+        CTRConfigBuilder::default()
+    }
+
+    /// The name of the application.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The author (publisher) of the application.
+    pub fn author(&self) -> &str {
+        &self.author
+    }
+
+    /// The description of the application.
+    pub fn description(&self) -> &str {
+        &self.description
+    }
+
+    /// The icon of the application.
+    pub fn icon(&self) -> &image::DynamicImage {
+        &self.icon
+    }
+
+    /// The file [`Self::icon`] was loaded from, if it was found by [`get_metadata`].
+    pub fn icon_path(&self) -> Option<&Path> {
+        self.icon_path.as_deref()
+    }
+
+    /// Whether [`Self::icon`] is libctru's default icon, because the package
+    /// has no `icon.png` of its own.
+    pub fn has_default_icon(&self) -> bool {
+        let Some(devkitpro) = env::var_os("DEVKITPRO") else {
+            return false;
+        };
+        self.icon_path() == Some(default_icon_path(Path::new(&devkitpro)).as_path())
+    }
+
+    /// The 24x24 icon of the application, if it isn't downscaled from [`Self::icon`].
+    pub fn small_icon(&self) -> Option<&image::DynamicImage> {
+        self.small_icon.as_ref()
+    }
+
+    /// The IDs for online and StreetPass features written to the SMDH.
+    pub fn network(&self) -> NetworkMetadata {
+        self.network
+    }
+
+    /// The path of the built `.elf` executable.
+    pub fn target_path(&self) -> &Path {
+        &self.target_path
+    }
+
+    /// The path of the manifest of the package the executable was built from.
+    pub fn cargo_manifest_path(&self) -> &Path {
+        &self.cargo_manifest_path
+    }
+
+    /// The name of the example the executable was built from, if any.
+    pub fn example(&self) -> Option<&str> {
+        self.example.as_deref()
+    }
+
+    /// The version of the package the executable was built from, if known.
+    pub fn version(&self) -> Option<&Version> {
+        self.version.as_ref()
+    }
+
+    /// The package version encoded as a 3DS title version, where the major,
+    /// minor and patch versions respectively take 6, 6 and 4 bits.
+    /// Returns an error if there is no version or a component is too large.
+    pub fn title_version(&self) -> Result<u16, String> {
+        let Some(version) = &self.version else {
+            return Err(String::from("no package version is known"));
+        };
+
+        let fields = [
+            ("major", version.major, 6),
+            ("minor", version.minor, 6),
+            ("patch", version.patch, 4),
+        ];
+        let mut title_version = 0;
+        for (name, value, bits) in fields {
+            let max = (1 << bits) - 1;
+            if value > max {
+                return Err(format!(
+                    "{name} version {value} of {version} is too large for a 3DS title version, \
+                    the maximum is {max}"
+                ));
+            }
+            title_version = (title_version << bits) | value as u16;
+        }
+
+        Ok(title_version)
+    }
+
+    /// Path of the `.3dsx` executable built from [`CTRConfig::target_path`].
+    pub fn path_3dsx(&self) -> PathBuf {
+        self.output_path("3dsx")
+    }
+
+    /// Path of the SMDH file built for the executable.
+    pub fn path_smdh(&self) -> PathBuf {
+        self.output_path("smdh")
+    }
+
+    /// Path of the directory used to combine multiple `RomFS` directories.
+    pub fn path_romfs(&self) -> PathBuf {
+        self.output_path("romfs")
+    }
+
+    /// Path of a file with `extension` next to the executable, named after it
+    /// unless an output name is set.
+    fn output_path(&self, extension: &str) -> PathBuf {
+        match &self.output_name {
+            Some(name) => self
+                .target_path
+                .with_file_name(format!("{name}.{extension}")),
+            None => self.target_path.with_extension(extension),
+        }
+    }
+}
+
+/// Builder for a [`CTRConfig`], created with [`CTRConfig::builder`].
+///
+/// Unset fields use the same defaults as [`get_metadata`] when the package
+/// doesn't specify them.
+pub struct CTRConfigBuilder {
+    config: CTRConfig,
+}
+
+impl Default for CTRConfigBuilder {
+    fn default() -> Self {
+        Self {
+            config: CTRConfig {
+                author: String::from("Unspecified Author"),
+                description: String::from("Homebrew Application"),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+impl CTRConfigBuilder {
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.config.name = name.into();
+        self
+    }
+
+    pub fn author(mut self, author: impl Into<String>) -> Self {
+        self.config.author = author.into();
+        self
+    }
+
+    pub fn description(mut self, description: impl Into<String>) -> Self {
+        self.config.description = description.into();
+        self
+    }
+
+    pub fn icon(mut self, icon: image::DynamicImage) -> Self {
+        self.config.icon = icon;
+        self
+    }
+
+    /// Set a 24x24 icon to use instead of downscaling the large one.
+    pub fn small_icon(mut self, small_icon: image::DynamicImage) -> Self {
+        self.config.small_icon = Some(small_icon);
+        self
+    }
+
+    pub fn network(mut self, network: NetworkMetadata) -> Self {
+        self.config.network = network;
+        self
+    }
+
+    /// Set the path of the `.elf` executable. Other built files are placed next to it.
+    pub fn target_path(mut self, target_path: impl Into<PathBuf>) -> Self {
+        self.config.target_path = target_path.into();
+        self
+    }
+
+    /// Set the path of the package manifest, which is read for settings like `romfs_dir`.
+    pub fn cargo_manifest_path(mut self, cargo_manifest_path: impl Into<PathBuf>) -> Self {
+        self.config.cargo_manifest_path = cargo_manifest_path.into();
+        self
+    }
+
+    /// Set the name of the example the executable was built from, if any.
+    pub fn example(mut self, example: impl Into<String>) -> Self {
+        self.config.example = Some(example.into());
+        self
+    }
+
+    /// Set the features of the package enabled in the build, which may select
+    /// another `romfs_dir`.
+    pub fn features(mut self, features: impl IntoIterator<Item = String>) -> Self {
+        self.config.features = features.into_iter().collect();
+        self
+    }
+
+    /// Set the name of the built files, instead of naming them after the executable.
+    pub fn output_name(mut self, output_name: impl Into<String>) -> Self {
+        self.config.output_name = Some(output_name.into());
+        self
+    }
+
+    pub fn version(mut self, version: Version) -> Self {
+        self.config.version = Some(version);
+        self
+    }
+
+    pub fn build(self) -> CTRConfig {
+        self.config
+    }
+}
+
+#[derive(Ord, PartialOrd, PartialEq, Eq, Debug)]
+pub struct CommitDate {
+    year: i32,
+    month: i32,
+    day: i32,
+}
+
+impl CommitDate {
+    fn parse(date: &str) -> Option<Self> {
+        let mut iter = date.splitn(3, '-');
+
+        let year = iter.next()?.parse().ok()?;
+        let month = iter.next()?.parse().ok()?;
+
+        // Ignore anything after the day, like a time or commit hash
+        let rest = iter.next()?;
+        let day_len = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let day = rest[..day_len].parse().ok()?;
+
+        Some(Self { year, month, day })
+    }
+}
+
+impl fmt::Display for CommitDate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+}
+
+const MINIMUM_COMMIT_DATE: CommitDate = CommitDate {
+    year: 2023,
+    month: 5,
     day: 31,
 };
 const MINIMUM_RUSTC_VERSION: Version = Version::new(1, 70, 0);
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    fn version_meta(release: &str) -> rustc_version::VersionMeta {
+        let verbose_version = format!(
+            "rustc {release} (0123456789 2023-05-31)\n\
+            binary: rustc\n\
+            commit-hash: 0123456789\n\
+            commit-date: 2023-05-31\n\
+            host: x86_64-unknown-linux-gnu\n\
+            release: {release}\n\
+            LLVM version: 16.0.4\n"
+        );
+
+        rustc_version::version_meta_for(&verbose_version).unwrap()
+    }
+
+    #[test]
+    fn channels() {
+        for (release, expected) in [
+            ("1.72.0-nightly", None),
+            ("1.72.0-dev", None),
+            ("1.71.0-beta.1", Some("beta")),
+            ("1.70.0", Some("stable")),
+        ] {
+            let channel = version_meta(release).channel;
+            assert_eq!(unsupported_channel(channel), expected, "{release}");
+        }
+    }
+
+    #[test]
+    fn parse_commit_date() {
+        for date in ["2023-05-31", "2023-05-31T00:00:00", "2023-05-31 (abcdef)"] {
+            assert_eq!(
+                CommitDate::parse(date),
+                Some(CommitDate {
+                    year: 2023,
+                    month: 5,
+                    day: 31
+                }),
+                "{date}"
+            );
+        }
+
+        for malformed in [
+            "",
+            "2023",
+            "2023-05",
+            "2023-05-",
+            "2023-May-31",
+            "x2023-05-31",
+        ] {
+            assert_eq!(CommitDate::parse(malformed), None, "{malformed}");
+        }
+    }
+
+    #[test]
+    fn config_builder() {
+        let config = CTRConfig::builder()
+            .name("app")
+            .target_path("/app/target/armv6k-nintendo-3ds/debug/app.elf")
+            .cargo_manifest_path("/app/Cargo.toml")
+            .build();
+
+        assert_eq!(config.name(), "app");
+        assert_eq!(config.author(), "Unspecified Author");
+        assert_eq!(config.description(), "Homebrew Application");
+        assert_eq!(
+            config.path_3dsx(),
+            Path::new("/app/target/armv6k-nintendo-3ds/debug/app.3dsx")
+        );
+        assert_eq!(config.cargo_manifest_path(), Path::new("/app/Cargo.toml"));
+        assert_eq!(config.example(), None);
+    }
+
+    #[test]
+    fn romfs_dir_size() {
+        let dir = env::temp_dir().join(format!("cargo-3ds-size-test-{}", process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("sub/empty")).unwrap();
+        std::fs::write(dir.join("a.bin"), [0; 100]).unwrap();
+        std::fs::write(dir.join("sub/b.bin"), [0; 28]).unwrap();
+        // Symlinked files are counted, but a symlink to a parent isn't followed
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink("../a.bin", dir.join("sub/c.bin")).unwrap();
+            std::os::unix::fs::symlink("..", dir.join("sub/parent")).unwrap();
+        }
+
+        let symlinked = if cfg!(unix) { 100 } else { 0 };
+
+        let no_exclude = RomfsExclude::default();
+        assert_eq!(dir_size(&dir, &no_exclude).unwrap(), 128 + symlinked);
+        assert!(dir_size(&dir.join("missing"), &no_exclude).is_err());
+
+        let exclude = RomfsExclude::new(&["sub".to_string()]).unwrap();
+        assert_eq!(dir_size(&dir, &exclude).unwrap(), 100);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn title_version() {
+        for (version, expected) in [
+            ("0.1.0", Ok(1 << 4)),
+            ("1.2.3", Ok(1 << 10 | 2 << 4 | 3)),
+            ("63.63.15-beta.1", Ok(u16::MAX)),
+            ("64.0.0", Err("major")),
+            ("0.64.0", Err("minor")),
+            ("0.1.16", Err("patch")),
+        ] {
+            let config = CTRConfig::builder()
+                .version(Version::parse(version).unwrap())
+                .build();
+            let result = config.title_version();
+
+            match expected {
+                Ok(expected) => assert_eq!(result, Ok(expected), "{version}"),
+                Err(field) => assert!(result.unwrap_err().starts_with(field), "{version}"),
+            }
+        }
+
+        assert!(CTRConfig::default().title_version().is_err());
+    }
+
+    #[test]
+    fn artifact_message() {
+        let config = CTRConfig::builder()
+            .target_path("/app/target/armv6k-nintendo-3ds/debug/app.elf")
+            .build();
+
+        let message = ArtifactMessage::new(&config, Some(&config.path_smdh()));
+        assert_eq!(
+            serde_json::to_string(&message).unwrap(),
+            r#"{"reason":"cargo-3ds-artifact","3dsx":"/app/target/armv6k-nintendo-3ds/debug/app.3dsx","smdh":"/app/target/armv6k-nintendo-3ds/debug/app.smdh"}"#
+        );
+
+        let message = ArtifactMessage::new(&config, None);
+        assert_eq!(message.smdh, None);
+    }
+
+    #[test]
+    fn profile_dirs() {
+        for (profile, dir) in [
+            ("dev", "debug"),
+            ("test", "debug"),
+            ("release", "release"),
+            ("bench", "release"),
+            ("release-small", "release-small"),
+        ] {
+            assert_eq!(profile_dir(profile), dir);
+        }
+    }
+
+    #[test]
+    fn retry_metadata() {
+        let mut calls = 0;
+        let result = retry_with_backoff(4, Duration::ZERO, || {
+            calls += 1;
+            if calls < 3 {
+                Err(calls)
+            } else {
+                Ok(calls)
+            }
+        });
+        assert_eq!(result, Ok(3));
+
+        let mut calls = 0;
+        let result: Result<(), _> = retry_with_backoff(4, Duration::ZERO, || {
+            calls += 1;
+            Err(calls)
+        });
+        assert_eq!(result, Err(4));
+    }
+
+    #[test]
+    fn escape_codes() {
+        for (text, expected) in [
+            ("plain text\n", "plain text\n"),
+            ("\x1b[31mred\x1b[0m text", "red text"),
+            ("\x1b[29;16HPress Start to exit", "Press Start to exit"),
+            ("\x1b[2Jcleared", "cleared"),
+            ("\x1bcreset", "reset"),
+            ("unterminated \x1b[31", "unterminated "),
+        ] {
+            assert_eq!(
+                String::from_utf8(strip_escape_codes(text.as_bytes())).unwrap(),
+                expected,
+                "{text:?}"
+            );
+        }
+
+        let mut output = Vec::new();
+        copy_without_escape_codes(&b"\x1b[32mline 1\x1b[0m\nline 2"[..], &mut output).unwrap();
+        assert_eq!(output, b"line 1\nline 2");
+    }
+
+    #[test]
+    fn up_to_date_executable() {
+        let dir = env::temp_dir().join(format!("cargo-3ds-test-{}", process::id()));
+        let debug = dir.join("debug/app.elf");
+        let release = dir.join("release/app.elf");
+        let missing = dir.join("debug/missing.elf");
+
+        for path in [&debug, &release] {
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, b"").unwrap();
+        }
+
+        let now = std::time::SystemTime::now();
+        std::fs::File::options()
+            .write(true)
+            .open(&debug)
+            .unwrap()
+            .set_modified(now - std::time::Duration::from_secs(60))
+            .unwrap();
+
+        let candidates = [
+            ("debug", debug.clone()),
+            ("missing", missing),
+            ("release", release.clone()),
+        ];
+        assert_eq!(find_latest_file(candidates), Some(("release", release)));
+        assert_eq!(find_latest_file([("missing", dir.join("nope.elf"))]), None);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn expand_env() {
+        let lookup = |name: &str| match name {
+            "DEVKITPRO" => Some(String::from("/opt/devkitpro")),
+            "ASSETS" => Some(String::from("assets")),
+            _ => None,
+        };
+
+        for (value, expected) in [
+            ("romfs", Ok("romfs")),
+            ("$DEVKITPRO/romfs", Ok("/opt/devkitpro/romfs")),
+            ("${ASSETS}_hd/$ASSETS", Ok("assets_hd/assets")),
+            ("$UNSET/romfs", Err(())),
+            ("${ASSETS", Err(())),
+            ("$/romfs", Err(())),
+            ("${}", Err(())),
+        ] {
+            let expanded = expand_vars(value, lookup);
+            assert_eq!(expanded.as_deref().map_err(|_| ()), expected, "{value:?}");
+        }
+    }
+
+    #[test]
+    fn path_args() {
+        let path = Path::new("/home/user/my 3ds app/target/débug/app.smdh");
+        assert_eq!(
+            path_arg("--smdh", path),
+            "--smdh=/home/user/my 3ds app/target/débug/app.smdh"
+        );
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::{OsStrExt, OsStringExt};
+
+            let path = Path::new(std::ffi::OsStr::from_bytes(b"romfs/\xff\xfe"));
+            assert_eq!(
+                path_arg("--romfs", path).into_vec(),
+                b"--romfs=romfs/\xff\xfe"
+            );
+        }
+    }
+
+    #[test]
+    fn rust_flags() {
+        let flags = [String::from("-L/opt/lib"), String::from("-lctru")];
+
+        assert_eq!(
+            append_rust_flags(None, None, &flags),
+            ("RUSTFLAGS", String::from(" -L/opt/lib -lctru"))
+        );
+        assert_eq!(
+            append_rust_flags(None, Some(String::from("-Cdebuginfo=0")), &flags),
+            ("RUSTFLAGS", String::from("-Cdebuginfo=0 -L/opt/lib -lctru"))
+        );
+        assert_eq!(
+            append_rust_flags(Some(String::new()), Some(String::from("-Cfoo")), &flags),
+            (
+                "CARGO_ENCODED_RUSTFLAGS",
+                String::from("-L/opt/lib\x1f-lctru")
+            )
+        );
+        assert_eq!(
+            append_rust_flags(Some(String::from("--cfg\x1ffoo bar")), None, &flags),
+            (
+                "CARGO_ENCODED_RUSTFLAGS",
+                String::from("--cfg\x1ffoo bar\x1f-L/opt/lib\x1f-lctru")
+            )
+        );
+    }
+
+    #[test]
+    fn configured_rust_flags() {
+        let config = |key: &str| match key {
+            "build.rustflags" => toml::from_str("v = ['-Cforce-frame-pointers', '--cfg=a b']")
+                .ok()
+                .and_then(|table: toml::Value| table.get("v").cloned()),
+            _ => None,
+        };
+        let no_config = |_: &str| None;
+
+        // Config-only flags are passed along with ours, keeping spaces in flags
+        let flags = config_rust_flags(&CargoEnv::default(), config);
+        assert_eq!(
+            flags.as_deref(),
+            Some(&["-Cforce-frame-pointers", "--cfg=a b"].map(String::from)[..])
+        );
+        assert_eq!(
+            append_rust_flags(
+                flags.map(|flags| flags.join("\x1f")),
+                None,
+                &["-lctru".into()]
+            ),
+            (
+                "CARGO_ENCODED_RUSTFLAGS",
+                String::from("-Cforce-frame-pointers\x1f--cfg=a b\x1f-lctru")
+            )
+        );
+        assert_eq!(config_rust_flags(&CargoEnv::default(), no_config), None);
+
+        // The environment overrides the configuration of the same key
+        let cargo_env = CargoEnv {
+            build_rust_flags: Some(String::from("-Cdebuginfo=0 -Cstrip=symbols")),
+            ..CargoEnv::default()
+        };
+        for lookup in [config, no_config] {
+            assert_eq!(
+                config_rust_flags(&cargo_env, lookup).unwrap(),
+                ["-Cdebuginfo=0", "-Cstrip=symbols"]
+            );
+        }
+
+        // Flags for the 3DS target take precedence over the ones for all targets
+        let cargo_env = CargoEnv {
+            target_rust_flags: Some(String::from("-Ctarget-cpu=mpcore")),
+            ..cargo_env
+        };
+        assert_eq!(
+            config_rust_flags(&cargo_env, config).unwrap(),
+            ["-Ctarget-cpu=mpcore"]
+        );
+    }
+
+    /// Build the cargo command for `args` with a fixed environment.
+    fn cargo_command(args: &[&str]) -> Command {
+        let input: Vec<&str> = ["cargo", "3ds"].iter().chain(args).copied().collect();
+        let command::Cargo::Input(input) =
+            command::Cargo::try_parse_from(input).unwrap_or_else(|e| panic!("{e}"));
+        let cargo_env = CargoEnv {
+            devkitpro: Some(String::from("/opt/devkitpro")),
+            rust_flags: Some(String::from("-Cdebuginfo=0")),
+            encoded_rust_flags: None,
+            rustdoc_flags: Some(String::from("--cfg docs")),
+            ..CargoEnv::default()
+        };
+
+        make_cargo_command_with_env(
+            &input,
+            &None,
+            &cargo_env,
+            &Cargo3dsMetadata::default(),
+            Path::new("/app"),
+        )
+    }
+
+    fn command_env<'a>(command: &'a Command, var: &str) -> Option<&'a str> {
+        command
+            .get_envs()
+            .find(|(name, _)| *name == var)
+            .and_then(|(_, value)| value?.to_str())
+    }
+
+    fn command_args(command: &Command) -> Vec<&str> {
+        command
+            .get_args()
+            .map(|arg| arg.to_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn build_command() {
+        let command = cargo_command(&["build", "--release"]);
+        let args = command_args(&command);
+
+        assert_eq!(args[1], "build");
+        assert!(args.windows(2).any(|pair| pair == ["--target", TARGET]));
+        assert!(args.contains(&"--release"));
+        assert_eq!(
+            command_env(&command, "RUSTFLAGS"),
+            Some("-Cdebuginfo=0 -L/opt/devkitpro/libctru/lib -lctru")
+        );
+        assert_eq!(command_env(&command, "RUSTDOCFLAGS"), None);
+    }
+
+    #[test]
+    fn test_command() {
+        let command = cargo_command(&["test", "--no-run"]);
+        let args = command_args(&command);
+
+        assert_eq!(args[1], "test");
+        assert!(args.contains(&"--no-run"));
+        assert!(command_env(&command, "RUSTFLAGS").is_some());
+        assert!(command_env(&command, "RUSTDOCFLAGS").is_some_and(|flags| {
+            flags.starts_with("--cfg docs") && flags.contains("--no-run")
+        }));
+    }
+
+    #[test]
+    fn passthrough_command() {
+        let command = cargo_command(&["clippy", "--all-targets"]);
+        let args = command_args(&command);
+
+        assert_eq!(args[1], "clippy");
+        assert!(args.contains(&"--all-targets"));
+        assert!(args.windows(2).any(|pair| pair == ["--target", TARGET]));
+        assert!(command_env(&command, "RUSTFLAGS").is_some());
+
+        // `new` doesn't compile anything, so it doesn't need the 3DS environment
+        let command = cargo_command(&["new", "my-app"]);
+        assert_eq!(command_args(&command)[1..], ["new", "my-app"]);
+        assert_eq!(command_env(&command, "RUSTFLAGS"), None);
+    }
+
+    #[test]
+    fn rust_flags_with_link_args() {
+        let flags = [String::from("-L/opt/lib"), String::from("-lctru")];
+        let user_flags = "-C link-arg=-L/usr/local/lib -C link-arg=-lfoo";
+
+        let (_, rust_flags) = append_rust_flags(None, Some(String::from(user_flags)), &flags);
+        let rust_flags: Vec<_> = rust_flags.split_whitespace().collect();
+
+        assert_eq!(
+            rust_flags,
+            [
+                "-C",
+                "link-arg=-L/usr/local/lib",
+                "-C",
+                "link-arg=-lfoo",
+                "-L/opt/lib",
+                "-lctru"
+            ]
+        );
+
+        let (_, rust_flags) = append_rust_flags(
+            Some(String::from("-Clink-arg=-L/usr/local/lib")),
+            None,
+            &flags,
+        );
+        assert_eq!(
+            rust_flags.split('\x1f').collect::<Vec<_>>(),
+            ["-Clink-arg=-L/usr/local/lib", "-L/opt/lib", "-lctru"]
+        );
+    }
+
+    #[test]
+    fn message_format_list() {
+        let format = "json,json-diagnostic-rendered-ansi";
+        let args = ["build", "--message-format", format];
+        let input: Vec<&str> = ["cargo", "3ds"].iter().chain(&args).copied().collect();
+        let command::Cargo::Input(mut input) =
+            command::Cargo::try_parse_from(input).unwrap_or_else(|e| panic!("{e}"));
+
+        let message_format = input.cmd.extract_message_format().unwrap();
+        assert_eq!(message_format.as_deref(), Some(format));
+        assert!(tees_output(&message_format, &input.cmd));
+
+        // The list is given back to cargo as a single value
+        let command = make_cargo_command_with_env(
+            &input,
+            &message_format,
+            &CargoEnv {
+                devkitpro: Some(String::from("/opt/devkitpro")),
+                rust_flags: Some(String::new()),
+                ..CargoEnv::default()
+            },
+            &Cargo3dsMetadata::default(),
+            Path::new("/app"),
+        );
+        let args = command_args(&command);
+        assert!(args
+            .windows(2)
+            .any(|pair| pair == ["--message-format", format]));
+        assert_eq!(
+            args.iter()
+                .filter(|arg| arg.contains("message-format"))
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn romfs_compress_support() {
+        let help = "Usage:\n    3dsxtool in.elf out.3dsx [options]\n\n\
+            Options:\n    --smdh=input.smdh    : Embeds SMDH information\n\
+            \x20   --romfs=dir          : Embeds RomFS into the file\n";
+        assert!(help_mentions_flag(help, "--romfs"));
+        assert!(!help_mentions_flag(help, ROMFS_COMPRESS_FLAG));
+
+        for help in [
+            "3dsxtool in.elf out.3dsx [--romfs-compress]",
+            "  --romfs-compress     : Compress the RomFS",
+            "[--romfs=dir|--romfs-compress]",
+        ] {
+            assert!(help_mentions_flag(help, ROMFS_COMPRESS_FLAG), "{help}");
+        }
+        assert!(!help_mentions_flag(
+            "--romfs-compression",
+            ROMFS_COMPRESS_FLAG
+        ));
+    }
+
+    #[test]
+    fn libtest_results() {
+        for (line, expected) in [
+            (
+                "test result: ok. 3 passed; 0 failed; 0 ignored; 0 measured; 0 filtered out",
+                Some(true),
+            ),
+            (
+                "test result: FAILED. 2 passed; 1 failed; 0 ignored; 0 measured; 0 filtered out",
+                Some(false),
+            ),
+            ("  test result: ok. 0 passed; 0 failed\r", Some(true)),
+            ("test tests::foo ... ok", None),
+            ("running 3 tests", None),
+        ] {
+            assert_eq!(libtest_result(line), expected, "{line}");
+        }
+
+        for (passed, line_result, expected) in [
+            (None, None, None),
+            (None, Some(true), Some(true)),
+            (Some(true), None, Some(true)),
+            (Some(true), Some(false), Some(false)),
+            (Some(false), Some(true), Some(false)),
+            (Some(false), None, Some(false)),
+        ] {
+            assert_eq!(merge_libtest_results(passed, line_result), expected);
+        }
+    }
+
+    #[test]
+    fn rust_src() {
+        let sysroot = env::temp_dir().join(format!("cargo-3ds-rust-src-sysroot-{}", process::id()));
+        let _ = std::fs::remove_dir_all(&sysroot);
+        std::fs::create_dir_all(sysroot.join("lib/rustlib/src/rust")).unwrap();
+        assert!(!has_rust_src(&sysroot));
+
+        std::fs::create_dir(sysroot.join("lib/rustlib/src/rust/library")).unwrap();
+        assert!(has_rust_src(&sysroot));
+
+        std::fs::remove_dir_all(&sysroot).unwrap();
+    }
+
+    #[test]
+    fn link_libs() {
+        let manifest_dir = Path::new("/app");
+        let settings = Cargo3dsMetadata::default();
+        assert_eq!(
+            link_flags("/opt/devkitpro", &settings, manifest_dir),
+            ["-L/opt/devkitpro/libctru/lib", "-lctru"]
+        );
+
+        let settings: Cargo3dsMetadata =
+            toml::from_str(r#"link_libs = ["citro2d", "citro3d", "ctru"]"#).unwrap();
+        assert_eq!(
+            link_flags("/opt/devkitpro", &settings, manifest_dir),
+            [
+                "-L/opt/devkitpro/libctru/lib",
+                "-lcitro2d",
+                "-lcitro3d",
+                "-lctru"
+            ]
+        );
+    }
+
+    #[test]
+    fn lib_paths() {
+        let settings: Cargo3dsMetadata = toml::from_str(
+            r#"lib_paths = ["lib", "/opt/portlibs/lib", "${CARGO_MANIFEST_DIR}/vendor"]"#,
+        )
+        .unwrap();
+        // Cargo defines CARGO_MANIFEST_DIR when running tests, so it takes precedence
+        let manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+
+        assert_eq!(
+            link_flags("/opt/devkitpro", &settings, Path::new("/app")),
+            [
+                String::from("-L/app/lib"),
+                String::from("-L/opt/portlibs/lib"),
+                format!("-L{manifest_dir}/vendor"),
+                String::from("-L/opt/devkitpro/libctru/lib"),
+                String::from("-lctru"),
+            ]
+        );
+    }
+
+    #[test]
+    fn rustdoc_flags_with_config() {
+        let config = || Some(vec![String::from("--cfg"), String::from("docs rs")]);
+
+        // The configured flags are ignored by cargo if RUSTDOCFLAGS is set
+        assert_eq!(
+            append_rustdoc_flags(Some(String::from("--cfg env")), config(), " --no-run"),
+            ("RUSTDOCFLAGS", String::from("--cfg env --no-run"))
+        );
+        assert_eq!(
+            append_rustdoc_flags(None, config(), " --no-run"),
+            (
+                "CARGO_ENCODED_RUSTDOCFLAGS",
+                String::from("--cfg\x1fdocs rs\x1f--no-run")
+            )
+        );
+        assert_eq!(
+            append_rustdoc_flags(None, config(), ""),
+            (
+                "CARGO_ENCODED_RUSTDOCFLAGS",
+                String::from("--cfg\x1fdocs rs")
+            )
+        );
+        assert_eq!(
+            append_rustdoc_flags(None, None, " --no-run"),
+            ("RUSTDOCFLAGS", String::from(" --no-run"))
+        );
+    }
+
+    #[test]
+    fn tee_rendered_diagnostics() {
+        // A rendered diagnostic (as with `json-diagnostic-rendered-ansi`) followed by an artifact
+        let stream = concat!(
+            r#"{"reason":"compiler-message","package_id":"app 0.1.0 (path+file:///app)","manifest_path":"/app/Cargo.toml","#,
+            r#""target":{"kind":["bin"],"crate_types":["bin"],"name":"app","src_path":"/app/src/main.rs","edition":"2021","doc":true,"doctest":false,"test":true},"#,
+            r#""message":{"rendered":"\u001b[33mwarning\u001b[0m: unused variable","children":[],"code":null,"level":"warning","message":"unused variable","spans":[]}}"#,
+            "\n",
+            r#"{"reason":"compiler-artifact","package_id":"app 0.1.0 (path+file:///app)","manifest_path":"/app/Cargo.toml","#,
+            r#""target":{"kind":["bin"],"crate_types":["bin"],"name":"app","src_path":"/app/src/main.rs","edition":"2021","doc":true,"doctest":false,"test":false},"#,
+            r#""profile":{"opt_level":"0","debuginfo":2,"debug_assertions":true,"overflow_checks":true,"test":false},"#,
+            r#""features":[],"filenames":["/app/target/armv6k-nintendo-3ds/debug/app.elf"],"executable":"/app/target/armv6k-nintendo-3ds/debug/app.elf","fresh":false}"#,
+            "\n",
+        );
+
+        let command::Cargo::Input(input) =
+            command::Cargo::try_parse_from(["cargo", "3ds", "build"])
+                .unwrap_or_else(|e| panic!("{e}"));
+        let format = Some(String::from("json-diagnostic-rendered-ansi"));
+        assert!(tees_output(&format, &input.cmd));
+        assert!(!tees_output(&None, &input.cmd));
+
+        // The rendered diagnostic reaches the user as is, escape codes included,
+        // and the executable is still found
+        let mut stdout = Vec::new();
+        let messages = read_messages(stream.as_bytes(), Some(&mut stdout)).unwrap();
+        assert_eq!(stdout, stream.as_bytes());
+        assert!(matches!(&messages[1], Message::CompilerArtifact(art)
+            if art.executable.as_deref() == Some("/app/target/armv6k-nintendo-3ds/debug/app.elf".into())));
+
+        let messages = read_messages(stream.as_bytes(), None::<io::Sink>).unwrap();
+        assert_eq!(messages.len(), 2);
+    }
+
+    #[test]
+    fn build_messages() {
+        let stream = concat!(
+            r#"{"reason":"compiler-message","package_id":"app 0.1.0 (path+file:///app)","manifest_path":"/app/Cargo.toml","#,
+            r#""target":{"kind":["bin"],"crate_types":["bin"],"name":"app","src_path":"/app/src/main.rs","edition":"2021","doc":true,"doctest":false,"test":true},"#,
+            r#""message":{"rendered":"warning: unused variable","children":[],"code":null,"level":"warning","message":"unused variable","spans":[]}}"#,
+            "\n",
+            r#"{"reason":"compiler-message","package_id":"app 0.1.0 (path+file:///app)","manifest_path":"/app/Cargo.toml","#,
+            r#""target":{"kind":["bin"],"crate_types":["bin"],"name":"app","src_path":"/app/src/main.rs","edition":"2021","doc":true,"doctest":false,"test":true},"#,
+            r#""message":{"rendered":"error: mismatched types","children":[],"code":null,"level":"error","message":"mismatched types","spans":[]}}"#,
+            "\n",
+        );
+        let mut messages = Message::parse_stream(stream.as_bytes())
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+
+        let diagnostics: Vec<_> = diagnostics(&messages)
+            .into_iter()
+            .map(|diagnostic| diagnostic.message.as_str())
+            .collect();
+        assert_eq!(diagnostics, ["unused variable", "mismatched types"]);
+
+        // Without a `build-finished` message, the build didn't complete
+        assert!(!build_succeeded(&messages));
+
+        for (success, expected) in [("false", false), ("true", true)] {
+            let finished = format!(r#"{{"reason":"build-finished","success":{success}}}"#);
+            messages.truncate(2);
+            messages.extend(Message::parse_stream(finished.as_bytes()).map(Result::unwrap));
+            assert_eq!(build_succeeded(&messages), expected);
+        }
+    }
+
+    fn artifact_target(kind: &str, name: &str, test: bool) -> Target {
+        let line = format!(
+            r#"{{"reason":"compiler-artifact","package_id":"app 0.1.0 (path+file:///app)","manifest_path":"/app/Cargo.toml","target":{{"kind":["{kind}"],"crate_types":["bin"],"name":"{name}","src_path":"/app/src/main.rs","edition":"2021","doc":true,"doctest":false,"test":{test}}},"profile":{{"opt_level":"0","debuginfo":2,"debug_assertions":true,"overflow_checks":true,"test":{test}}},"features":[],"filenames":[],"executable":null,"fresh":true}}"#
+        );
+
+        match Message::parse_stream(line.as_bytes()).next() {
+            Some(Ok(Message::CompilerArtifact(artifact))) => artifact.target,
+            other => panic!("unexpected message {other:?}"),
+        }
+    }
+
+    #[test]
+    fn romfs_override() {
+        let fixture = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/hello");
+        let config = CTRConfig::builder()
+            .cargo_manifest_path(fixture.join("Cargo.toml"))
+            .build();
+
+        assert_eq!(
+            romfs_paths_with_override(&config, None),
+            (vec![fixture.join("romfs")], false)
+        );
+        assert_eq!(
+            romfs_paths_with_override(&config, Some(OsString::new())),
+            (vec![fixture.join("romfs")], false)
+        );
+        assert_eq!(
+            romfs_paths_with_override(&config, Some(OsString::from("ci-assets"))),
+            (vec![fixture.join("ci-assets")], false)
+        );
+        assert_eq!(
+            romfs_paths_with_override(&config, Some(OsString::from("/opt/assets"))),
+            (vec![PathBuf::from("/opt/assets")], false)
+        );
+    }
+
+    #[test]
+    fn example_romfs() {
+        let metadata: Cargo3dsMetadata = toml::from_str(
+            r#"
+            romfs_dir = "romfs"
+
+            [example.foo]
+            romfs_dir = "examples/foo-romfs"
+            "#,
+        )
+        .unwrap();
+
+        for (kind, name, test, expected) in [
+            ("bin", "app", false, "romfs"),
+            ("bin", "app", true, "romfs"),
+            ("lib", "app", true, "romfs"),
+            ("example", "foo", false, "examples/foo-romfs"),
+            ("example", "foo", true, "examples/foo-romfs"),
+            ("example", "bar", true, "romfs"),
+        ] {
+            let target = artifact_target(kind, name, test);
+            let setting = metadata.romfs_dir(example_name(&target), &[]);
+
+            assert_eq!(
+                setting,
+                Some(vec![expected]),
+                "{kind} {name} (test: {test})"
+            );
+        }
+    }
+
+    #[test]
+    fn feature_romfs() {
+        let metadata: Cargo3dsMetadata = toml::from_str(
+            r#"
+            romfs_dir = "romfs"
+
+            [feature.hd-assets]
+            romfs_dir = "romfs-hd"
+
+            [feature.music]
+            romfs_dir = ["music", "music-extra"]
+
+            [example.foo]
+            romfs_dir = "examples/foo-romfs"
+            "#,
+        )
+        .unwrap();
+
+        for (example, features, expected) in [
+            (None, &[][..], &["romfs"][..]),
+            (None, &["default", "sound"], &["romfs"]),
+            (None, &["default", "hd-assets"], &["romfs-hd"]),
+            (
+                None,
+                &["hd-assets", "music"],
+                &["romfs-hd", "music", "music-extra"],
+            ),
+            (Some("foo"), &["hd-assets"], &["examples/foo-romfs"]),
+            (Some("bar"), &["hd-assets"], &["romfs-hd"]),
+        ] {
+            let features: Vec<String> = features.iter().map(|f| f.to_string()).collect();
+            assert_eq!(
+                metadata.romfs_dir(example, &features),
+                Some(expected.to_vec()),
+                "{example:?} {features:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn read_settings() {
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+
+        let settings = read_cargo_3ds_metadata(&fixtures.join("hello/Cargo.toml")).unwrap();
+        assert_eq!(settings.name.as_deref(), Some("Hello"));
+        assert_eq!(
+            settings.romfs_dir,
+            Some(RomfsDir::Single(String::from("romfs")))
+        );
+
+        // No `[package.metadata.cargo-3ds]` table
+        let settings = read_cargo_3ds_metadata(&fixtures.join("broken/Cargo.toml")).unwrap();
+        assert_eq!(settings, Cargo3dsMetadata::default());
+
+        assert!(read_cargo_3ds_metadata(&fixtures.join("missing/Cargo.toml")).is_err());
+    }
+
+    #[test]
+    fn merged_settings_tables() {
+        let dir = env::temp_dir().join(format!("cargo-3ds-merged-settings-{}", process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let manifest_path = dir.join("Cargo.toml");
+        std::fs::write(
+            &manifest_path,
+            "[package]\nname = \"merged\"\n\n\
+            [package.metadata.cargo-3ds.network]\ncec_id = 1\n\n\
+            [package.metadata.cargo-3ds.example.foo]\nromfs_dir = \"foo-romfs\"\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join(CONFIG_FILE_NAME),
+            "[network]\ncec_id = 2\nmatch_maker_id = 3\n\n\
+            [example.bar]\nromfs_dir = \"bar-romfs\"\n",
+        )
+        .unwrap();
+
+        let settings = read_cargo_3ds_metadata(&manifest_path).unwrap();
+        // The manifest wins for each key, not for each table
+        assert_eq!(
+            settings.network,
+            NetworkMetadata {
+                cec_id: 1,
+                match_maker_id: 3,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            settings.romfs_dir(Some("foo"), &[]),
+            Some(vec!["foo-romfs"])
+        );
+        assert_eq!(
+            settings.romfs_dir(Some("bar"), &[]),
+            Some(vec!["bar-romfs"])
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn malformed_settings() {
+        let settings: Cargo3dsMetadata = toml::from_str(r#"romfs_dir = ["a", "b"]"#).unwrap();
+        assert_eq!(
+            settings.romfs_dir.as_ref().map(RomfsDir::dirs),
+            Some(&[String::from("a"), String::from("b")][..])
+        );
+
+        let settings: Cargo3dsMetadata = toml::from_str("network.cec_id = 0x12345").unwrap();
+        assert_eq!(
+            settings.network,
+            NetworkMetadata {
+                cec_id: 0x12345,
+                ..Default::default()
+            }
+        );
+
+        for malformed in [
+            "romfs_dir = 5",
+            r#"romfs_dir = ["a", 5]"#,
+            "romfs_size_limit = -1",
+            r#"romfs_size_limit = "256""#,
+            "name = 1",
+            "example = 1",
+            r#"example.foo = { romfs_dir = true }"#,
+            "network.cec_id = -1",
+        ] {
+            let table: toml::value::Table = toml::from_str(malformed).unwrap();
+            assert!(Cargo3dsMetadata::from_table(table).is_err(), "{malformed}");
+        }
+    }
+
+    #[test]
+    fn small_icon_encoding() {
+        let mut icon = image::RgbImage::new(SMALL_ICON_SIZE, SMALL_ICON_SIZE);
+        icon.put_pixel(1, 0, image::Rgb([255, 0, 0]));
+        icon.put_pixel(0, 1, image::Rgb([0, 255, 0]));
+        icon.put_pixel(7, 7, image::Rgb([0, 0, 255]));
+        icon.put_pixel(8, 0, image::Rgb([255, 255, 255]));
+        icon.put_pixel(0, 8, image::Rgb([255, 255, 255]));
+
+        let data = encode_icon(&icon.into());
+        let pixel = |index: usize| u16::from_le_bytes([data[index * 2], data[index * 2 + 1]]);
+
+        assert_eq!(data.len(), SMALL_ICON_BYTES);
+        assert_eq!(pixel(0), 0);
+        assert_eq!(pixel(1), 0xf800);
+        assert_eq!(pixel(2), 0x07e0);
+        assert_eq!(pixel(63), 0x001f);
+        // The next tile is to the right, and the one after it starts the next row
+        assert_eq!(pixel(64), 0xffff);
+        assert_eq!(pixel(3 * 64), 0xffff);
+    }
+
+    #[test]
+    fn undisplayable_smdh_chars() {
+        assert_eq!(undisplayable_chars("Hello, World! ~"), []);
+        assert_eq!(undisplayable_chars("Café Über\nÅse ÿ"), []);
+        assert_eq!(undisplayable_chars("ゲーム €5 ゲ"), ['€', 'ゲ', 'ム', 'ー']);
+        assert_eq!(undisplayable_chars("tab\there"), ['\t']);
+    }
+
+    #[test]
+    fn desc_suffix() {
+        assert_eq!(
+            expand_desc_suffix("build {date} {git_hash}", Some("abc1234"), "2024-06-01"),
+            "build 2024-06-01 abc1234"
+        );
+        assert_eq!(
+            expand_desc_suffix("build {date} {git_hash}", None, "2024-06-01"),
+            "build 2024-06-01"
+        );
+        assert_eq!(expand_desc_suffix("nightly", None, "2024-06-01"), "nightly");
+    }
+
+    #[test]
+    fn utc_dates() {
+        for (secs, date) in [
+            (0, "1970-01-01"),
+            (951_782_400, "2000-02-29"),
+            (1_717_243_199, "2024-06-01"),
+            (1_735_689_600, "2025-01-01"),
+        ] {
+            let time = SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+            assert_eq!(utc_date(time), date, "{secs}");
+        }
+    }
+
+    #[test]
+    fn long_desc_truncation() {
+        assert_eq!(truncate_utf16("short", 128), (String::from("short"), false));
+
+        let long = "a".repeat(130);
+        assert_eq!(truncate_utf16(&long, 128), ("a".repeat(128), true));
+
+        // Characters outside the BMP take two UTF-16 code units
+        let emoji = "a".repeat(127) + "\u{1F980}";
+        assert_eq!(truncate_utf16(&emoji, 128), ("a".repeat(127), true));
+    }
+
+    #[test]
+    fn rust_flags_cache() {
+        let dir = env::temp_dir().join(format!("cargo-3ds-rustflags-test-{}", process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let cache = dir.join("flags");
+
+        assert_eq!(update_rust_flags_cache(&cache, "RUSTFLAGS=-lctru"), None);
+        assert_eq!(update_rust_flags_cache(&cache, "RUSTFLAGS=-lctru"), None);
+        assert_eq!(
+            update_rust_flags_cache(&cache, "RUSTFLAGS=-Copt-level=s -lctru"),
+            Some(String::from("RUSTFLAGS=-lctru"))
+        );
+        assert_eq!(
+            std::fs::read_to_string(&cache).unwrap(),
+            "RUSTFLAGS=-Copt-level=s -lctru"
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn tool_versions() {
+        for (output, version) in [
+            ("3dslink v0.6.3\n", Some("0.6.3")),
+            (
+                "3DSXTool (version 1.2, built 2023)\nUsage: ...",
+                Some("1.2"),
+            ),
+            ("Usage: 3dsxtool [options] input.elf output.3dsx\n", None),
+            ("", None),
+        ] {
+            assert_eq!(parse_tool_version(output).as_deref(), version, "{output:?}");
+        }
+    }
+
+    #[test]
+    fn romfs_file_list() {
+        let dir = env::temp_dir().join(format!("cargo-3ds-romfs-files-{}", process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("gfx/.git")).unwrap();
+        std::fs::write(dir.join("hello.txt"), "hello").unwrap();
+        std::fs::write(dir.join("gfx/sprite.t3x"), [0; 10]).unwrap();
+        std::fs::write(dir.join("gfx/.git/HEAD"), "").unwrap();
+
+        assert_eq!(
+            romfs_files(&dir).unwrap(),
+            [
+                (PathBuf::from("gfx/.git/HEAD"), 0),
+                (PathBuf::from("gfx/sprite.t3x"), 10),
+                (PathBuf::from("hello.txt"), 5),
+            ]
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unintended_romfs_files() {
+        for path in [
+            ".DS_Store",
+            "gfx/Thumbs.db",
+            ".git/config",
+            "levels/.svn/entries",
+            "level1.txt~",
+            ".level1.txt.swp",
+            ".#level1.txt",
+            "save.bak",
+        ] {
+            assert!(is_unintended_file(Path::new(path)), "{path}");
+        }
+        for path in ["hello.txt", "gfx/sprite.t3x", ".hidden-asset", "git/config"] {
+            assert!(!is_unintended_file(Path::new(path)), "{path}");
+        }
+    }
+
+    #[test]
+    fn romfs_exclude_patterns() {
+        let exclude = RomfsExclude::new(&[
+            "*.psd".to_string(),
+            "/src/*.txt".to_string(),
+            "drafts".to_string(),
+            "/music".to_string(),
+        ])
+        .unwrap();
+
+        for path in [
+            "logo.psd",
+            "gfx/logo.psd",
+            "src/notes.txt",
+            "levels/drafts/1.txt",
+            "music/1.ogg",
+        ] {
+            assert!(exclude.excludes_file(Path::new(path)), "{path}");
+        }
+        for path in [
+            "logo.png",
+            "notes.txt",
+            "src/gfx/notes.txt",
+            "drafts.txt",
+            "sfx/music/1.ogg",
+        ] {
+            assert!(!exclude.excludes_file(Path::new(path)), "{path}");
+        }
+
+        assert!(RomfsExclude::new(&["[".to_string()]).is_err());
+    }
+
+    #[test]
+    fn merge_romfs_with_exclude() {
+        let dir = env::temp_dir().join(format!("cargo-3ds-romfs-exclude-{}", process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let source = dir.join("romfs");
+        std::fs::create_dir_all(source.join("gfx/drafts")).unwrap();
+        std::fs::write(source.join("hello.txt"), "hello").unwrap();
+        std::fs::write(source.join("gfx/logo.psd"), "").unwrap();
+        std::fs::write(source.join("gfx/logo.t3x"), "").unwrap();
+        std::fs::write(source.join("gfx/drafts/1.t3x"), "").unwrap();
+        std::fs::write(source.join("gfx/drafts/2.t3x"), "").unwrap();
+
+        let exclude = RomfsExclude::new(&["*.psd".to_string(), "drafts".to_string()]).unwrap();
+        let merged = dir.join("merged");
+        assert_eq!(merge_romfs_dirs(&[source], &merged, &exclude), Ok(3));
+
+        let files: Vec<_> = romfs_files(&merged)
+            .unwrap()
+            .into_iter()
+            .map(|(path, _)| path)
+            .collect();
+        assert_eq!(files, [Path::new("gfx/logo.t3x"), Path::new("hello.txt")]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn merge_romfs_conflicts() {
+        let dir = env::temp_dir().join(format!("cargo-3ds-romfs-merge-{}", process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let (base, extra, conflict) = (dir.join("base"), dir.join("extra"), dir.join("conflict"));
+        std::fs::create_dir_all(base.join("gfx")).unwrap();
+        std::fs::create_dir_all(extra.join("gfx")).unwrap();
+        std::fs::create_dir_all(&conflict).unwrap();
+        std::fs::write(base.join("gfx/logo.t3x"), "").unwrap();
+        std::fs::write(extra.join("gfx/hd.t3x"), "").unwrap();
+        std::fs::write(conflict.join("gfx"), "").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink("..", base.join("gfx/parent")).unwrap();
+
+        // Directories are merged, and the symlink to a parent isn't followed
+        let merged = dir.join("merged");
+        let no_exclude = RomfsExclude::default();
+        assert_eq!(
+            merge_romfs_dirs(&[base.clone(), extra], &merged, &no_exclude),
+            Ok(0)
+        );
+        let files: Vec<_> = romfs_files(&merged)
+            .unwrap()
+            .into_iter()
+            .map(|(path, _)| path)
+            .collect();
+        assert_eq!(files, [Path::new("gfx/hd.t3x"), Path::new("gfx/logo.t3x")]);
+
+        // A file where another directory has a directory is a conflict
+        for sources in [[base.clone(), conflict.clone()], [conflict, base]] {
+            let error = merge_romfs_dirs(&sources, &merged, &no_exclude).unwrap_err();
+            assert!(error.contains("provided by both"), "{error}");
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn romfs_size_from_header() {
+        let mut header = vec![0; EXTENDED_3DSX_HEADER_SIZE];
+        header[..4].copy_from_slice(b"3DSX");
+        header[4..6].copy_from_slice(&44u16.to_le_bytes());
+        header[40..44].copy_from_slice(&0x1000u32.to_le_bytes());
+        assert_eq!(embedded_romfs_size(&header, 0x1800), Some(0x800));
+
+        // No RomFS
+        header[40..44].copy_from_slice(&0u32.to_le_bytes());
+        assert_eq!(embedded_romfs_size(&header, 0x1800), None);
+
+        // No extended header
+        header[4..6].copy_from_slice(&32u16.to_le_bytes());
+        header[40..44].copy_from_slice(&0x1000u32.to_le_bytes());
+        assert_eq!(embedded_romfs_size(&header, 0x1800), None);
+        assert_eq!(embedded_romfs_size(&header[..32], 0x1800), None);
+    }
+
+    #[test]
+    fn smdh_file() {
+        let path = env::temp_dir().join(format!("cargo-3ds-check-{}.smdh", process::id()));
+
+        let mut smdh = vec![0; SMDH_SIZE as usize];
+        smdh[..4].copy_from_slice(b"SMDH");
+        std::fs::write(&path, &smdh).unwrap();
+        assert_eq!(check_smdh(&path), Ok(()));
+
+        std::fs::write(&path, &smdh[..0x2040]).unwrap();
+        assert!(check_smdh(&path).is_err());
+
+        smdh[..4].copy_from_slice(b"3DSX");
+        std::fs::write(&path, &smdh).unwrap();
+        assert!(check_smdh(&path).is_err());
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(check_smdh(&path).is_err());
+    }
+
+    #[test]
+    fn network_settings() {
+        let mut smdh = vec![0; 0x36C0];
+        write_network_settings(&mut smdh, &NetworkMetadata::default());
+        assert!(smdh.iter().all(|&byte| byte == 0));
+
+        let network = NetworkMetadata {
+            match_maker_id: 0x1234_5678,
+            match_maker_bit_id: 0x0102_0304_0506_0708,
+            cec_id: 0xABCD_EF01,
+        };
+        write_network_settings(&mut smdh, &network);
+        assert_eq!(smdh[0x201C..0x2020], [0x78, 0x56, 0x34, 0x12]);
+        assert_eq!(
+            smdh[0x2020..0x2028],
+            [0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01]
+        );
+        assert_eq!(smdh[0x2034..0x2038], [0x01, 0xEF, 0xCD, 0xAB]);
+    }
+
+    #[test]
+    fn svg_icons() {
+        let dir = env::temp_dir().join(format!("cargo-3ds-svg-icon-{}", process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let svg = r##"<svg xmlns="http://www.w3.org/2000/svg" width="96" height="64">
+            <rect width="96" height="64" fill="#ff0000"/>
+        </svg>"##;
+
+        for name in ["icon.svg", "icon.SVG", "icon"] {
+            let path = dir.join(name);
+            std::fs::write(&path, svg).unwrap();
+            assert!(is_svg(&path), "{name}");
+        }
+        std::fs::write(dir.join("icon"), [0x89, b'P', b'N', b'G']).unwrap();
+        assert!(!is_svg(&dir.join("icon")));
+        assert!(!is_svg(&dir.join("icon.png")));
+
+        let icon = load_icon(&dir.join("icon.svg"), LARGE_ICON_SIZE);
+        if cfg!(feature = "svg") {
+            let icon = icon.unwrap().to_rgba8();
+            assert_eq!(icon.dimensions(), (LARGE_ICON_SIZE, LARGE_ICON_SIZE));
+            // Scaled to fit, so the top and bottom rows are left transparent
+            assert_eq!(icon.get_pixel(24, 24).0, [255, 0, 0, 255]);
+            assert_eq!(icon.get_pixel(24, 0).0[3], 0);
+        } else {
+            assert!(icon.is_err());
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn small_icon_size() {
+        let path = Path::new("icon-small.png");
+
+        let icon = fit_small_icon(image::RgbImage::new(24, 24).into(), path);
+        assert_eq!((icon.width(), icon.height()), (24, 24));
+
+        let icon = fit_small_icon(image::RgbImage::new(48, 32).into(), path);
+        assert_eq!((icon.width(), icon.height()), (24, 24));
+    }
+
+    #[test]
+    fn icon_transparency() {
+        let opaque = image::RgbaImage::from_pixel(48, 48, image::Rgba([0, 0, 0, 255]));
+        assert!(!has_transparency(&opaque.into()));
+
+        let mut transparent = image::RgbaImage::from_pixel(48, 48, image::Rgba([0, 0, 0, 255]));
+        transparent.put_pixel(0, 0, image::Rgba([0, 0, 0, 0]));
+        assert!(has_transparency(&transparent.into()));
+
+        let rgb = image::RgbImage::new(48, 48);
+        assert!(!has_transparency(&rgb.into()));
+    }
+
+    #[test]
+    fn default_icon() {
+        assert_eq!(
+            default_icon_path(Path::new("/opt/devkitpro")),
+            Path::new("/opt/devkitpro/libctru/default_icon.png")
+        );
+        assert_eq!(
+            default_icon_path(Path::new("/opt/devkitpro/")),
+            Path::new("/opt/devkitpro/libctru/default_icon.png")
+        );
+    }
+}
@@ -1,27 +1,116 @@
-use std::process;
+use std::backtrace::Backtrace;
+use std::panic::{self, AssertUnwindSafe};
+use std::{env, process};
 
-use cargo_3ds::command::Cargo;
-use cargo_3ds::{check_rust_version, run_cargo};
-use clap::Parser;
+use cargo_3ds::command::{Build, Cargo, Input};
+use cargo_3ds::{check_rust_version, run_cargo, CTRConfig};
 
 fn main() {
     check_rust_version();
 
-    let Cargo::Input(mut input) = Cargo::parse();
+    let Cargo::Input(input) = Cargo::parse_with_aliases();
+    set_panic_hook(input.verbose);
 
-    let message_format = match input.cmd.extract_message_format() {
-        Ok(fmt) => fmt,
-        Err(msg) => {
-            eprintln!("{msg}");
-            process::exit(1)
+    // The panic is already reported by the hook. Unwinding instead of exiting
+    // there lets guards like the signal forwarding clean up first.
+    if panic::catch_unwind(AssertUnwindSafe(|| run(input))).is_err() {
+        process::exit(1);
+    }
+}
+
+/// Check the options of `input`, then build (and run) it for each selected profile.
+fn run(mut input: Input) {
+    let checked = input.cmd.extract_message_format().and_then(|fmt| {
+        input.check_verbosity()?;
+        input.cmd.check_target()?;
+        input.cmd.check_options()?;
+        input.cmd.check_profiles()?;
+        Ok(fmt)
+    });
+    let message_format = checked.unwrap_or_else(|msg| {
+        eprintln!("{msg}");
+        process::exit(1)
+    });
+
+    let profiles = input.cmd.take_profiles();
+    if profiles.is_empty() {
+        if let Err(code) = build(&input, message_format) {
+            process::exit(code);
+        }
+        return;
+    }
+
+    let keep_going = input.cmd.build_args().is_some_and(|build| build.keep_going);
+    let mut built = Vec::new();
+    let mut failed = Vec::new();
+    for profile in profiles {
+        input.cmd.set_profile(&profile);
+        match build(&input, message_format.clone()) {
+            Ok(Some(config)) => built.push((profile, config.path_3dsx())),
+            Ok(None) => {}
+            Err(code) if keep_going => failed.push((profile, code)),
+            Err(code) => process::exit(code),
+        }
+    }
+
+    let skips_3dsx = input.cmd.build_args().is_some_and(Build::skips_3dsx);
+    if !input.quiet && !skips_3dsx {
+        for (profile, path_3dsx) in built {
+            eprintln!("Built {profile}: {}", path_3dsx.display());
+        }
+    }
+
+    for (profile, _) in &failed {
+        eprintln!("error: failed to build {profile}");
+    }
+    if let Some(&(_, code)) = failed.first() {
+        process::exit(code);
+    }
+}
+
+/// Run cargo, then the callback of the command with its output. Returns cargo's
+/// exit code if it fails.
+fn build(input: &Input, message_format: Option<String>) -> Result<Option<CTRConfig>, i32> {
+    let messages = if input.cmd.should_run_cargo() {
+        let (status, messages) = run_cargo(input, message_format);
+
+        // Exit with cargo's own code before looking for the executable,
+        // which would otherwise fail with a less helpful error.
+        if !status.success() {
+            return Err(status.code().unwrap_or(1));
         }
+
+        messages
+    } else {
+        Vec::new()
     };
 
-    let (status, messages) = run_cargo(&input, message_format);
+    Ok(input.cmd.run_callback(&messages))
+}
 
-    if !status.success() {
-        process::exit(status.code().unwrap_or(1));
+/// Report panics as a one-line error instead of Rust's panic message, since
+/// they are mostly failures like a missing tool or an unreadable file rather
+/// than bugs. With `--verbose`, the backtrace is printed too, and if
+/// `RUST_BACKTRACE` is set the default panic output is kept.
+fn set_panic_hook(verbose: bool) {
+    if env::var_os("RUST_BACKTRACE").is_some() {
+        return;
     }
 
-    input.cmd.run_callback(&messages);
+    panic::set_hook(Box::new(move |info| {
+        let payload = info.payload();
+        let message = payload
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("unexpected error");
+
+        eprintln!("error: {message}");
+        if verbose {
+            if let Some(location) = info.location() {
+                eprintln!("note: at {location}");
+            }
+            eprintln!("{}", Backtrace::force_capture());
+        }
+    }));
 }
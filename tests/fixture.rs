@@ -0,0 +1,488 @@
+//! Tests using the fixture package in `tests/fixtures/hello`.
+
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Once;
+use std::{env, fs};
+
+use cargo_3ds::command::Cargo;
+use cargo_3ds::{get_metadata, get_romfs_paths, make_cargo_command, CTRConfig};
+use cargo_metadata::{Message, MetadataCommand};
+use clap::Parser;
+
+fn fixture_manifest() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/hello/Cargo.toml")
+}
+
+/// Point `DEVKITPRO` to a fake installation, which only has the default icon.
+fn devkitpro() -> PathBuf {
+    static INIT: Once = Once::new();
+
+    let devkitpro = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/devkitpro");
+    INIT.call_once(|| env::set_var("DEVKITPRO", &devkitpro));
+    devkitpro
+}
+
+#[test]
+fn romfs_path() {
+    let manifest_path = fixture_manifest();
+    let config = fixture_config(&target_dir());
+
+    let (paths, is_default) = get_romfs_paths(&config);
+
+    assert_eq!(paths, [manifest_path.parent().unwrap().join("romfs")]);
+    assert!(!is_default);
+    assert!(paths[0].join("hello.txt").is_file());
+}
+
+#[test]
+fn workspace_member_romfs() {
+    let workspace_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/workspace");
+    let target_dir = workspace_dir.join("target/armv6k-nintendo-3ds/debug");
+    let config = build_config(&workspace_dir.join("Cargo.toml"), "member", &target_dir);
+
+    assert_eq!(
+        config.cargo_manifest_path(),
+        workspace_dir.join("member/Cargo.toml")
+    );
+
+    // The RomFS is relative to the member, not the workspace root
+    let (paths, is_default) = get_romfs_paths(&config);
+    assert_eq!(paths, [workspace_dir.join("member/romfs")]);
+    assert!(is_default);
+    assert!(paths[0].join("hello.txt").is_file());
+}
+
+#[test]
+fn cargo_command() {
+    let devkitpro = devkitpro();
+    let Cargo::Input(input) =
+        Cargo::parse_from(["cargo", "3ds", "build", "--features", "foo", "--release"]);
+
+    let command = make_cargo_command(&input, &None);
+    let args: Vec<_> = command.get_args().collect();
+
+    assert_eq!(
+        command.get_program(),
+        env::var_os("CARGO").unwrap_or("cargo".into())
+    );
+    assert_eq!(args[1], "build");
+    assert!(args
+        .windows(2)
+        .any(|pair| pair == [OsStr::new("--target"), OsStr::new("armv6k-nintendo-3ds")]));
+    assert!(args.contains(&OsStr::new("--release")));
+    assert!(args.contains(&OsStr::new("--features=foo")));
+    assert!(!args.contains(&OsStr::new("--quiet")));
+
+    let rust_flags = command
+        .get_envs()
+        .find(|(var, _)| *var == "RUSTFLAGS" || *var == "CARGO_ENCODED_RUSTFLAGS")
+        .and_then(|(_, value)| value)
+        .expect("rustflags should be set")
+        .to_string_lossy();
+
+    assert!(rust_flags.contains(&format!("-L{}/libctru/lib", devkitpro.display())));
+    assert!(rust_flags.contains("-lctru"));
+}
+
+#[test]
+fn metadata_artifact() {
+    let target_dir = target_dir();
+    let config = fixture_config(&target_dir);
+
+    assert_eq!(config.name(), "Hello");
+    assert_eq!(config.author(), "Fixture Author");
+    assert_eq!(config.description(), "Fixture package for cargo-3ds tests");
+    assert_eq!(config.target_path(), target_dir.join("hello.elf"));
+    assert_eq!(config.cargo_manifest_path(), fixture_manifest());
+    assert_eq!(config.example(), None);
+    assert_eq!(config.version().unwrap().to_string(), "0.1.0");
+    assert_eq!(
+        config.icon_path(),
+        Some(devkitpro().join("libctru/default_icon.png").as_path())
+    );
+    assert!(config.has_default_icon());
+    assert_eq!(config.path_3dsx(), target_dir.join("hello.3dsx"));
+    assert_eq!(config.path_smdh(), target_dir.join("hello.smdh"));
+}
+
+#[test]
+fn custom_target_dir() {
+    devkitpro();
+    let manifest_path = fixture_manifest();
+    let target_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("custom-target");
+    let elf_dir = target_dir.join("armv6k-nintendo-3ds/debug");
+    fs::create_dir_all(&elf_dir).unwrap();
+    fs::write(elf_dir.join("hello.elf"), "").unwrap();
+
+    let Cargo::Input(input) = Cargo::parse_from([
+        OsStr::new("cargo"),
+        OsStr::new("3ds"),
+        OsStr::new("run"),
+        OsStr::new("--no-build"),
+        OsStr::new("--target-dir"),
+        target_dir.as_os_str(),
+        OsStr::new("--manifest-path"),
+        manifest_path.as_os_str(),
+    ]);
+
+    let command = make_cargo_command(&input, &None);
+    let args: Vec<_> = command.get_args().collect();
+    assert!(args
+        .windows(2)
+        .any(|pair| pair == [OsStr::new("--target-dir"), target_dir.as_os_str()]));
+
+    // Without a build, the previously built executable is used
+    let build = input.cmd.build_args().unwrap();
+    let config = get_metadata(
+        &[],
+        &build.metadata_args(),
+        Some("hello"),
+        Some(&build.previous_build()),
+    );
+
+    assert_eq!(config.target_path(), elf_dir.join("hello.elf"));
+    assert_eq!(config.path_3dsx(), elf_dir.join("hello.3dsx"));
+}
+
+/// A stand-in for cargo whose builds fail with exit code 7, so that builds fail
+/// with a code of their own, whatever the toolchain can build. Other commands
+/// are run by the real cargo, from `$REAL_CARGO`.
+#[cfg(unix)]
+fn failing_cargo() -> PathBuf {
+    use std::os::unix::fs::PermissionsExt;
+
+    let stub = Path::new(env!("CARGO_TARGET_TMPDIR")).join("failing-cargo.sh");
+    fs::write(
+        &stub,
+        "#!/bin/sh\n\
+        case \" $* \" in *\" build \"*) echo 'stub build failed' >&2; exit 7;; esac\n\
+        exec \"$REAL_CARGO\" \"$@\"\n",
+    )
+    .unwrap();
+    fs::set_permissions(&stub, fs::Permissions::from_mode(0o755)).unwrap();
+    stub
+}
+
+#[test]
+#[cfg(unix)]
+fn failed_build_exit_code() {
+    let manifest_path =
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/broken/Cargo.toml");
+    let stub = failing_cargo();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_cargo-3ds"))
+        .args(["3ds", "build", "--manifest-path"])
+        .arg(&manifest_path)
+        .env("CARGO", &stub)
+        .env("REAL_CARGO", env::var_os("CARGO").unwrap_or("cargo".into()))
+        .env("DEVKITPRO", devkitpro())
+        .env("CARGO_3DS_SKIP_VERSION_CHECK", "1")
+        .env(
+            "CARGO_TARGET_DIR",
+            Path::new(env!("CARGO_TARGET_TMPDIR")).join("broken"),
+        )
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    // cargo's own exit code is kept, rather than failing to find the executable
+    assert_eq!(output.status.code(), Some(7), "{stderr}");
+    assert!(stderr.contains("stub build failed"), "{stderr}");
+    assert!(!stderr.contains("No executable found"), "{stderr}");
+}
+
+#[test]
+#[cfg(unix)]
+fn keep_going_profiles() {
+    let manifest_path =
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/broken/Cargo.toml");
+    let stub = failing_cargo();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_cargo-3ds"))
+        .args([
+            "3ds",
+            "build",
+            "--profiles",
+            "debug,release",
+            "--keep-going",
+        ])
+        .arg("--manifest-path")
+        .arg(&manifest_path)
+        .env("CARGO", &stub)
+        .env("REAL_CARGO", env::var_os("CARGO").unwrap_or("cargo".into()))
+        .env("DEVKITPRO", devkitpro())
+        .env("CARGO_3DS_SKIP_VERSION_CHECK", "1")
+        .env(
+            "CARGO_TARGET_DIR",
+            Path::new(env!("CARGO_TARGET_TMPDIR")).join("broken"),
+        )
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    // Both profiles are built, and both failures are reported at the end
+    assert_eq!(output.status.code(), Some(7), "{stderr}");
+    assert_eq!(stderr.matches("stub build failed").count(), 2, "{stderr}");
+    assert!(stderr.contains("error: failed to build debug"), "{stderr}");
+    assert!(
+        stderr.contains("error: failed to build release"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn custom_profile() {
+    devkitpro();
+    let manifest_path = fixture_manifest();
+    let target_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("profile-target");
+    let profile_dir = target_dir.join("armv6k-nintendo-3ds/release-small");
+    fs::create_dir_all(&profile_dir).unwrap();
+    fs::write(profile_dir.join("hello.elf"), "").unwrap();
+
+    let Cargo::Input(input) = Cargo::parse_from([
+        OsStr::new("cargo"),
+        OsStr::new("3ds"),
+        OsStr::new("run"),
+        OsStr::new("--no-build"),
+        OsStr::new("--profile"),
+        OsStr::new("release-small"),
+        OsStr::new("--target-dir"),
+        target_dir.as_os_str(),
+        OsStr::new("--manifest-path"),
+        manifest_path.as_os_str(),
+    ]);
+
+    let command = make_cargo_command(&input, &None);
+    assert!(command
+        .get_args()
+        .any(|arg| arg == "--profile=release-small"));
+
+    let build = input.cmd.build_args().unwrap();
+    let config = get_metadata(
+        &[],
+        &build.metadata_args(),
+        Some("hello"),
+        Some(&build.previous_build()),
+    );
+
+    assert_eq!(config.target_path(), profile_dir.join("hello.elf"));
+}
+
+#[test]
+fn manifest_path_from_parent_dir() {
+    devkitpro();
+    // Tests run in the directory of cargo-3ds's own package
+    let manifest_path = Path::new("tests/fixtures/hello/Cargo.toml");
+    let target_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("manifest-path-target");
+    let elf_dir = target_dir.join("armv6k-nintendo-3ds/debug");
+    fs::create_dir_all(&elf_dir).unwrap();
+    fs::write(elf_dir.join("hello.elf"), "").unwrap();
+
+    let Cargo::Input(input) = Cargo::parse_from([
+        OsStr::new("cargo"),
+        OsStr::new("3ds"),
+        OsStr::new("run"),
+        OsStr::new("--no-build"),
+        OsStr::new("--manifest-path"),
+        manifest_path.as_os_str(),
+        OsStr::new("--target-dir"),
+        target_dir.as_os_str(),
+    ]);
+
+    let command = make_cargo_command(&input, &None);
+    let args: Vec<_> = command.get_args().collect();
+    assert!(args
+        .windows(2)
+        .any(|pair| pair == [OsStr::new("--manifest-path"), manifest_path.as_os_str()]));
+
+    let build = input.cmd.build_args().unwrap();
+    let config = get_metadata(
+        &[],
+        &build.metadata_args(),
+        Some("hello"),
+        Some(&build.previous_build()),
+    );
+
+    assert_eq!(config.cargo_manifest_path(), fixture_manifest());
+    assert_eq!(config.target_path(), elf_dir.join("hello.elf"));
+
+    // The RomFS is found next to the given manifest, not in the current directory
+    let (paths, _) = get_romfs_paths(&config);
+    assert_eq!(paths, [fixture_manifest().parent().unwrap().join("romfs")]);
+}
+
+#[test]
+fn link_libs_of_selected_package() {
+    devkitpro();
+    let manifest_path =
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/workspace/Cargo.toml");
+    let Cargo::Input(input) = Cargo::parse_from([
+        OsStr::new("cargo"),
+        OsStr::new("3ds"),
+        OsStr::new("build"),
+        OsStr::new("--manifest-path"),
+        manifest_path.as_os_str(),
+        OsStr::new("-p"),
+        OsStr::new("member"),
+    ]);
+
+    let command = make_cargo_command(&input, &None);
+    let rust_flags = command
+        .get_envs()
+        .find(|(var, _)| *var == "RUSTFLAGS" || *var == "CARGO_ENCODED_RUSTFLAGS")
+        .and_then(|(_, value)| value)
+        .expect("rustflags should be set")
+        .to_string_lossy();
+
+    // The settings are those of the selected member, not the virtual workspace
+    assert!(rust_flags.contains("-lcitro2d"), "{rust_flags}");
+}
+
+#[test]
+fn previous_build_of_selected_package() {
+    devkitpro();
+    let workspace_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/workspace");
+    let target_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("previous-build-target");
+    let debug_dir = target_dir.join("armv6k-nintendo-3ds/debug");
+    let release_dir = target_dir.join("armv6k-nintendo-3ds/release");
+    fs::create_dir_all(&debug_dir).unwrap();
+    fs::create_dir_all(&release_dir).unwrap();
+
+    // Newer executables of another package and profile must not be picked
+    fs::write(debug_dir.join("member.elf"), "").unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(10));
+    fs::write(debug_dir.join("sibling.elf"), "").unwrap();
+    fs::write(release_dir.join("member.elf"), "").unwrap();
+
+    let manifest_path = workspace_dir.join("Cargo.toml");
+    let Cargo::Input(input) = Cargo::parse_from([
+        OsStr::new("cargo"),
+        OsStr::new("3ds"),
+        OsStr::new("run"),
+        OsStr::new("--no-build"),
+        OsStr::new("--manifest-path"),
+        manifest_path.as_os_str(),
+        OsStr::new("--target-dir"),
+        target_dir.as_os_str(),
+        OsStr::new("-p"),
+        OsStr::new("member"),
+        OsStr::new("--features"),
+        OsStr::new("hd-assets"),
+    ]);
+
+    let build = input.cmd.build_args().unwrap();
+    let config = get_metadata(
+        &[],
+        &build.metadata_args(),
+        None,
+        Some(&build.previous_build()),
+    );
+
+    assert_eq!(config.target_path(), debug_dir.join("member.elf"));
+    assert_eq!(
+        config.cargo_manifest_path(),
+        workspace_dir.join("member/Cargo.toml")
+    );
+
+    // The requested features select the RomFS, like when cargo reports them
+    let (paths, _) = get_romfs_paths(&config);
+    assert_eq!(paths, [workspace_dir.join("member/romfs-hd")]);
+}
+
+#[test]
+fn selected_bin() {
+    let manifest_path = fixture_manifest();
+    let target_dir = target_dir();
+    let metadata_args = [format!("--manifest-path={}", manifest_path.display())];
+
+    // `other` is built last, so it's used unless another binary is selected
+    let messages = build_messages(&manifest_path, "hello", &["hello", "other"], &target_dir);
+    let config = get_metadata(&messages, &metadata_args, None, None);
+    assert_eq!(config.target_path(), target_dir.join("other.elf"));
+
+    let Cargo::Input(input) = Cargo::parse_from(["cargo", "3ds", "run", "--bin", "hello"]);
+    let build = input.cmd.build_args().unwrap();
+    let config = get_metadata(
+        &messages,
+        &metadata_args,
+        build.selected_target().as_deref(),
+        None,
+    );
+    assert_eq!(config.target_path(), target_dir.join("hello.elf"));
+    assert_eq!(config.name(), "Hello");
+}
+
+fn target_dir() -> PathBuf {
+    fixture_manifest()
+        .parent()
+        .unwrap()
+        .join("target/armv6k-nintendo-3ds/debug")
+}
+
+/// Get the config for the fixture from the messages of a build outputting
+/// `hello.elf` in `target_dir`.
+fn fixture_config(target_dir: &Path) -> CTRConfig {
+    build_config(&fixture_manifest(), "hello", target_dir)
+}
+
+/// Get the config from the messages of a build of the package `name` in the
+/// workspace of `manifest_path`, outputting `<name>.elf` in `target_dir`.
+fn build_config(manifest_path: &Path, name: &str, target_dir: &Path) -> CTRConfig {
+    get_metadata(
+        &build_messages(manifest_path, name, &[name], target_dir),
+        &[format!("--manifest-path={}", manifest_path.display())],
+        None,
+        None,
+    )
+}
+
+/// Get the messages of a build of the binaries `bins` of the package `name` in
+/// the workspace of `manifest_path`, outputting `<bin>.elf` in `target_dir`.
+fn build_messages(
+    manifest_path: &Path,
+    name: &str,
+    bins: &[&str],
+    target_dir: &Path,
+) -> Vec<Message> {
+    devkitpro();
+    let metadata = MetadataCommand::new()
+        .manifest_path(manifest_path)
+        .no_deps()
+        .exec()
+        .unwrap();
+    let package = metadata
+        .packages
+        .iter()
+        .find(|package| package.name == name)
+        .unwrap();
+
+    let artifact = |kind: &str, target: &str, executable: Option<PathBuf>| {
+        format!(
+            r#"{{"reason":"compiler-artifact","package_id":"{id}","manifest_path":{manifest},"target":{{"kind":["{kind}"],"crate_types":["{kind}"],"name":"{target}","src_path":"src/main.rs","edition":"2021","doc":true,"doctest":false,"test":false}},"profile":{{"opt_level":"0","debuginfo":2,"debug_assertions":true,"overflow_checks":true,"test":false}},"features":[],"filenames":[],"executable":{executable},"fresh":false}}"#,
+            id = package.id,
+            manifest = serialize_path(package.manifest_path.as_std_path()),
+            executable = executable.map_or("null".into(), |path| serialize_path(&path)),
+        )
+    };
+
+    let mut output: Vec<_> = bins
+        .iter()
+        .map(|bin| artifact("bin", bin, Some(target_dir.join(format!("{bin}.elf")))))
+        .collect();
+    output.extend([
+        // Artifacts without an executable, like build scripts or libraries, are skipped
+        artifact("lib", name, None),
+        String::from(r#"{"reason":"build-finished","success":true}"#),
+    ]);
+
+    Message::parse_stream(output.join("\n").as_bytes())
+        .map(Result::unwrap)
+        .collect()
+}
+
+/// Quote a path as a JSON string.
+fn serialize_path(path: &Path) -> String {
+    format!("{:?}", path.display().to_string())
+}